@@ -1,36 +1,449 @@
-use proc_macro2::Span;
+use crate::bound::{Implied, InferredBound};
+use proc_macro2::{Span, TokenStream};
+use quote::{quote, quote_spanned, ToTokens};
 use syn::parse::{Error, Parse, ParseStream, Result};
-use syn::Token;
+use syn::{token, Attribute, Ident, Lifetime, LitStr, Path, Token};
 
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 pub struct Args {
     pub local: bool,
+    pub local_if: Option<TokenStream>,
+    pub sync: bool,
+    pub static_future: bool,
+    pub alloc: Option<Path>,
+    pub future: Option<Path>,
+    pub wrap: Option<Path>,
+    pub forward: Vec<Ident>,
+    pub mock: Option<TokenStream>,
+    pub dyn_trait: Option<Ident>,
+    pub variants: Option<Variants>,
+    pub pointers: Vec<Pointer>,
+    pub implies: Vec<Implied>,
+    pub instrument: Option<Instrument>,
+}
+
+#[derive(Clone)]
+pub struct Variants {
+    pub send: Ident,
+    pub local: Ident,
+}
+
+// Options of #[async_trait(instrument(...))], which places the future of each
+// method in a tracing span.
+#[derive(Clone)]
+pub struct Instrument {
+    pub level: Ident,
+    pub skip: Vec<Ident>,
+}
+
+// A smart pointer type declared with #[async_trait(receiver(Name = Arc))],
+// which receivers like `self: Name<Self>` are treated the same as.
+#[derive(Clone)]
+pub struct Pointer {
+    pub ident: Ident,
+    pub kind: PointerKind,
+}
+
+#[derive(Clone, Copy)]
+pub enum PointerKind {
+    // &T
+    Shared,
+    // &mut T
+    Exclusive,
+    // Box<T>, Pin<T>
+    Owned,
+    Arc,
+    Rc,
 }
 
 mod kw {
     syn::custom_keyword!(Send);
+    syn::custom_keyword!(Sync);
+    syn::custom_keyword!(cfg);
+    syn::custom_keyword!(alloc);
+    syn::custom_keyword!(future);
+    syn::custom_keyword!(wrap);
+    syn::custom_keyword!(forward);
+    syn::custom_keyword!(mock);
+    syn::custom_keyword!(variants);
+    syn::custom_keyword!(receiver);
+    syn::custom_keyword!(implies);
+    syn::custom_keyword!(instrument);
+    syn::custom_keyword!(level);
+    syn::custom_keyword!(skip);
+    syn::custom_keyword!(Local);
 }
 
 impl Parse for Args {
     fn parse(input: ParseStream) -> Result<Self> {
-        match try_parse(input) {
-            Ok(args) if input.is_empty() => Ok(args),
-            _ => Err(error()),
+        let mut args = Args {
+            local: false,
+            local_if: None,
+            sync: false,
+            static_future: false,
+            alloc: None,
+            future: None,
+            wrap: None,
+            forward: Vec::new(),
+            mock: None,
+            dyn_trait: None,
+            variants: None,
+            pointers: Vec::new(),
+            implies: Vec::new(),
+            instrument: None,
+        };
+        parse_list(input, |input| {
+            if input.peek(Token![?]) {
+                parse_local(input, &mut args)?;
+                if input.peek(Token![if]) {
+                    input.parse::<Token![if]>()?;
+                    input.parse::<kw::cfg>()?;
+                    let content;
+                    syn::parenthesized!(content in input);
+                    args.local = false;
+                    args.local_if = Some(content.parse()?);
+                }
+                Ok(())
+            } else if input.peek(kw::Sync) {
+                parse_sync(input, &mut args)
+            } else if input.peek(kw::instrument) {
+                parse_instrument(input, &mut args)
+            } else if input.peek(kw::alloc) {
+                input.parse::<kw::alloc>()?;
+                input.parse::<Token![=]>()?;
+                args.alloc = Some(input.call(Path::parse_mod_style)?);
+                Ok(())
+            } else if input.peek(kw::future) {
+                input.parse::<kw::future>()?;
+                input.parse::<Token![=]>()?;
+                args.future = Some(input.call(Path::parse_mod_style)?);
+                Ok(())
+            } else if input.peek(kw::wrap) {
+                input.parse::<kw::wrap>()?;
+                input.parse::<Token![=]>()?;
+                args.wrap = Some(input.call(Path::parse_mod_style)?);
+                Ok(())
+            } else if input.peek(Token![dyn]) {
+                input.parse::<Token![dyn]>()?;
+                input.parse::<Token![=]>()?;
+                args.dyn_trait = Some(input.parse()?);
+                Ok(())
+            } else if input.peek(kw::forward) {
+                input.parse::<kw::forward>()?;
+                let content;
+                syn::parenthesized!(content in input);
+                parse_list(&content, |input| {
+                    let target: Ident = input.parse()?;
+                    if !["Box", "Arc", "Ref", "RefMut"].iter().any(|t| target == t) {
+                        let msg = "expected one of: Box, Arc, Ref, RefMut";
+                        return Err(Error::new(target.span(), msg));
+                    }
+                    args.forward.push(target);
+                    Ok(())
+                })
+            } else if input.peek(kw::mock) {
+                input.parse::<kw::mock>()?;
+                args.mock = Some(if input.peek(token::Paren) {
+                    let content;
+                    syn::parenthesized!(content in input);
+                    content.parse()?
+                } else {
+                    quote!(test)
+                });
+                Ok(())
+            } else if input.peek(kw::variants) {
+                let variants = input.parse::<kw::variants>()?;
+                let content;
+                syn::parenthesized!(content in input);
+                let mut send = None;
+                let mut local = None;
+                parse_list(&content, |input| {
+                    if input.peek(kw::Send) {
+                        input.parse::<kw::Send>()?;
+                        input.parse::<Token![=]>()?;
+                        send = Some(input.parse()?);
+                    } else if input.peek(kw::Local) {
+                        input.parse::<kw::Local>()?;
+                        input.parse::<Token![=]>()?;
+                        local = Some(input.parse()?);
+                    } else {
+                        return Err(input.error("expected `Send = ...` or `Local = ...`"));
+                    }
+                    Ok(())
+                })?;
+                match (send, local) {
+                    (Some(send), Some(local)) => {
+                        args.variants = Some(Variants { send, local });
+                        Ok(())
+                    }
+                    _ => {
+                        let msg = "expected variants(Send = ..., Local = ...)";
+                        Err(Error::new(variants.span, msg))
+                    }
+                }
+            } else if input.peek(kw::receiver) {
+                input.parse::<kw::receiver>()?;
+                let content;
+                syn::parenthesized!(content in input);
+                parse_list(&content, |input| {
+                    let ident: Ident = input.parse()?;
+                    input.parse::<Token![=]>()?;
+                    let kind = if input.peek(Token![&]) {
+                        input.parse::<Token![&]>()?;
+                        if input.parse::<Option<Token![mut]>>()?.is_some() {
+                            PointerKind::Exclusive
+                        } else {
+                            PointerKind::Shared
+                        }
+                    } else {
+                        let kind: Ident = input.parse()?;
+                        if kind == "Box" {
+                            PointerKind::Owned
+                        } else if kind == "Arc" {
+                            PointerKind::Arc
+                        } else if kind == "Rc" {
+                            PointerKind::Rc
+                        } else {
+                            let msg = "expected one of: &, &mut, Box, Arc, Rc";
+                            return Err(Error::new(kind.span(), msg));
+                        }
+                    };
+                    args.pointers.push(Pointer { ident, kind });
+                    Ok(())
+                })
+            } else if input.peek(kw::implies) {
+                input.parse::<kw::implies>()?;
+                let content;
+                syn::parenthesized!(content in input);
+                parse_list(&content, |input| {
+                    let path = input.call(Path::parse_mod_style)?;
+                    input.parse::<Token![=]>()?;
+                    let mut bounds = Vec::new();
+                    loop {
+                        if input.peek(kw::Send) {
+                            input.parse::<kw::Send>()?;
+                            bounds.push(InferredBound::Send);
+                        } else if input.peek(kw::Sync) {
+                            input.parse::<kw::Sync>()?;
+                            bounds.push(InferredBound::Sync);
+                        } else {
+                            return Err(input.error("expected Send or Sync"));
+                        }
+                        if !input.peek(Token![+]) {
+                            break;
+                        }
+                        input.parse::<Token![+]>()?;
+                    }
+                    args.implies.push(Implied { path, bounds });
+                    Ok(())
+                })
+            } else {
+                Err(error())
+            }
+        })?;
+        Ok(args)
+    }
+}
+
+fn parse_list(
+    input: ParseStream,
+    mut parse_one: impl FnMut(ParseStream) -> Result<()>,
+) -> Result<()> {
+    while !input.is_empty() {
+        parse_one(input)?;
+        if input.is_empty() {
+            break;
         }
+        input.parse::<Token![,]>()?;
     }
+    Ok(())
+}
+
+fn parse_local(input: ParseStream, args: &mut Args) -> Result<()> {
+    input.parse::<Token![?]>()?;
+    input.parse::<kw::Send>()?;
+    args.local = true;
+    Ok(())
+}
+
+fn parse_sync(input: ParseStream, args: &mut Args) -> Result<()> {
+    input.parse::<kw::Sync>()?;
+    args.sync = true;
+    Ok(())
 }
 
-fn try_parse(input: ParseStream) -> Result<Args> {
-    if input.peek(Token![?]) {
-        input.parse::<Token![?]>()?;
-        input.parse::<kw::Send>()?;
-        Ok(Args { local: true })
-    } else {
-        Ok(Args { local: false })
+fn parse_instrument(input: ParseStream, args: &mut Args) -> Result<()> {
+    input.parse::<kw::instrument>()?;
+    let mut instrument = Instrument {
+        level: Ident::new("INFO", Span::call_site()),
+        skip: Vec::new(),
+    };
+    if input.peek(token::Paren) {
+        let content;
+        syn::parenthesized!(content in input);
+        parse_list(&content, |input| {
+            if input.peek(kw::level) {
+                input.parse::<kw::level>()?;
+                input.parse::<Token![=]>()?;
+                let level: LitStr = input.parse()?;
+                let value = level.value();
+                if !["trace", "debug", "info", "warn", "error"].contains(&value.as_str()) {
+                    let msg =
+                        "expected one of: \"trace\", \"debug\", \"info\", \"warn\", \"error\"";
+                    return Err(Error::new(level.span(), msg));
+                }
+                instrument.level = Ident::new(&value.to_uppercase(), level.span());
+            } else if input.peek(kw::skip) {
+                input.parse::<kw::skip>()?;
+                let content;
+                syn::parenthesized!(content in input);
+                parse_list(&content, |input| {
+                    instrument.skip.push(input.parse()?);
+                    Ok(())
+                })?;
+            } else {
+                return Err(input.error("expected `level = ...` or `skip(...)`"));
+            }
+            Ok(())
+        })?;
     }
+    args.instrument = Some(instrument);
+    Ok(())
 }
 
 fn error() -> Error {
     let msg = "expected #[async_trait] or #[async_trait(?Send)]";
     Error::new(Span::call_site(), msg)
 }
+
+impl Args {
+    // Apply any #[async_trait(...)] attributes written on an individual method
+    // on top of the arguments of the enclosing trait or impl, and remove those
+    // attributes from the method.
+    pub fn with_method_attrs(&self, attrs: &mut Vec<Attribute>) -> Result<Args> {
+        let mut args = self.clone();
+        let mut errors = Ok(());
+        attrs.retain(|attr| {
+            if !is_async_trait_attr(attr) {
+                return true;
+            }
+            let result = attr.parse_args_with(|input: ParseStream| {
+                if input.is_empty() {
+                    return Err(input.error("unexpected end of input"));
+                }
+                parse_list(input, |input| {
+                    if input.peek(kw::Sync) {
+                        parse_sync(input, &mut args)
+                    } else if input.peek(kw::instrument) {
+                        parse_instrument(input, &mut args)
+                    } else if input.peek(Lifetime) {
+                        let lifetime: Lifetime = input.parse()?;
+                        if lifetime.ident != "static" {
+                            return Err(Error::new(lifetime.span(), "expected 'static"));
+                        }
+                        args.static_future = true;
+                        Ok(())
+                    } else {
+                        parse_local(input, &mut args)
+                    }
+                })
+            });
+            if let Err(mut error) = result {
+                let msg = "expected #[async_trait(?Send)], #[async_trait(Sync)], #[async_trait('static)] or #[async_trait(instrument)]";
+                error.combine(Error::new_spanned(attr, msg));
+                match &mut errors {
+                    Ok(()) => errors = Err(error),
+                    Err(errors) => errors.combine(error),
+                }
+            }
+            false
+        });
+        errors.map(|()| args)
+    }
+
+    // Path of the crate providing Arc in the expansion. Arc is not in the
+    // prelude, so unlike Box it cannot be left for the caller's scope to
+    // resolve, and crates that are no_std need to point this at their `alloc`
+    // crate using #[async_trait(alloc = ::alloc)].
+    pub fn alloc(&self, span: Span) -> TokenStream {
+        match &self.alloc {
+            Some(alloc) => respan(alloc.to_token_stream(), span),
+            None => quote_spanned!(span=> ::std),
+        }
+    }
+
+    // Lifetime that the returned future is bounded by. Methods marked
+    // #[async_trait('static)] return a future that borrows nothing.
+    pub fn future_lifetime(&self, span: Span) -> Lifetime {
+        if self.static_future {
+            Lifetime::new("'static", span)
+        } else {
+            Lifetime::new("'async_trait", span)
+        }
+    }
+
+    // Without #[async_trait(alloc = path)], Box is left for the caller's scope
+    // to resolve, which is the prelude's Box in std crates and typically an
+    // import of `alloc::boxed::Box` in no_std ones.
+    pub fn boxed(&self, span: Span) -> TokenStream {
+        match &self.alloc {
+            Some(alloc) => {
+                let alloc = respan(alloc.to_token_stream(), span);
+                quote_spanned!(span=> #alloc::boxed::Box)
+            }
+            None => quote_spanned!(span=> Box),
+        }
+    }
+
+    // Expression that turns the given future into the return type of the
+    // transformed method. With #[async_trait(future = path)] the boxed future
+    // is converted into `path<'async_trait, Output>` using its From impl.
+    pub fn boxed_future(&self, span: Span, future: TokenStream) -> TokenStream {
+        let boxed = self.boxed(span);
+        if self.future.is_none() {
+            return quote_spanned!(span=> #boxed::pin(#future));
+        }
+        let send = if self.local {
+            None
+        } else {
+            Some(quote_spanned!(span=> ::core::marker::Send +))
+        };
+        let sync = if self.sync {
+            Some(quote_spanned!(span=> ::core::marker::Sync +))
+        } else {
+            None
+        };
+        let lifetime = self.future_lifetime(span);
+        quote_spanned! {span=>
+            ::core::convert::From::from(
+                #boxed::pin(#future)
+                    as ::core::pin::Pin<#boxed<
+                        dyn ::core::future::Future<Output = _> + #send #sync #lifetime
+                    >>
+            )
+        }
+    }
+}
+
+fn respan(tokens: TokenStream, span: Span) -> TokenStream {
+    tokens
+        .into_iter()
+        .map(|mut token| {
+            token.set_span(span);
+            token
+        })
+        .collect()
+}
+
+pub fn find_async_trait_attr(attrs: &[Attribute]) -> Option<&Attribute> {
+    attrs.iter().find(|attr| is_async_trait_attr(attr))
+}
+
+pub fn is_async_trait_attr(attr: &Attribute) -> bool {
+    let path = attr.path();
+    path.is_ident("async_trait")
+        || path.leading_colon.is_none()
+            && path.segments.len() == 2
+            && path.segments[0].ident == "async_trait"
+            && path.segments[1].ident == "async_trait"
+}
@@ -0,0 +1,60 @@
+use syn::parse::{Error, Result};
+use syn::punctuated::Punctuated;
+use syn::{parse_quote, Attribute, Meta, Token};
+
+// Input:
+//     #[async_trait::inner(allow(unused_variables))]
+//     #[async_trait::outer(must_use)]
+//     async fn f(&self) {...}
+//
+// Output:
+//     #[must_use]
+//     fn f<'async_trait>(&'async_trait self) -> Pin<Box<...>> {
+//         Box::pin(#[allow(unused_variables)] async move {...})
+//     }
+//
+// Removes the #[async_trait::inner(...)] attributes from the method and
+// returns their content, which belongs on the async block of the method body.
+// The content of #[async_trait::outer(...)] stays on the method itself, which
+// is also where any other attribute ends up.
+pub fn route_attrs(attrs: &mut Vec<Attribute>) -> Result<Vec<Attribute>> {
+    let mut inner = Vec::new();
+    let mut routed = Vec::new();
+    for attr in attrs.drain(..) {
+        let is_inner = is_routing_attr(&attr, "inner");
+        if is_inner || is_routing_attr(&attr, "outer") {
+            let metas = attr.parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)?;
+            let metas = metas
+                .into_iter()
+                .map(|meta| -> Attribute { parse_quote!(#[#meta]) });
+            if is_inner {
+                inner.extend(metas);
+            } else {
+                routed.extend(metas);
+            }
+        } else {
+            routed.push(attr);
+        }
+    }
+    *attrs = routed;
+    Ok(inner)
+}
+
+// An async fn without a body has no async block for the content of
+// #[async_trait::inner(...)] to go on.
+pub fn reject_inner_attrs(attrs: &[Attribute]) -> Result<()> {
+    match attrs.iter().find(|attr| is_routing_attr(attr, "inner")) {
+        Some(attr) => {
+            let msg = "#[async_trait::inner(...)] has no effect on a method without a body";
+            Err(Error::new_spanned(attr, msg))
+        }
+        None => Ok(()),
+    }
+}
+
+fn is_routing_attr(attr: &Attribute, name: &str) -> bool {
+    let path = attr.path();
+    path.segments.len() == 2
+        && path.segments[0].ident == "async_trait"
+        && path.segments[1].ident == name
+}
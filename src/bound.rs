@@ -1,18 +1,36 @@
 use proc_macro2::{Ident, Span, TokenStream};
 use quote::{quote, ToTokens};
 use syn::punctuated::Punctuated;
-use syn::{Token, TypeParamBound};
+use syn::{Path, Token, TypeParamBound};
 
 pub type Supertraits = Punctuated<TypeParamBound, Token![+]>;
 
+#[derive(Clone)]
 pub enum InferredBound {
     Send,
     Sync,
 }
 
-pub fn has_bound(supertraits: &Supertraits, bound: &InferredBound) -> bool {
+// A supertrait declared with #[async_trait(implies(ThreadSafe = Send + Sync))]
+// to guarantee the given bounds.
+#[derive(Clone)]
+pub struct Implied {
+    pub path: Path,
+    pub bounds: Vec<InferredBound>,
+}
+
+pub fn has_bound(supertraits: &Supertraits, implied: &[Implied], bound: &InferredBound) -> bool {
     for supertrait in supertraits {
         if let TypeParamBound::Trait(supertrait) = supertrait {
+            if implied.iter().any(|implied| {
+                ends_with(&supertrait.path, &implied.path)
+                    && implied
+                        .bounds
+                        .iter()
+                        .any(|implied| implied.as_str() == bound.as_str())
+            }) {
+                return true;
+            }
             if supertrait.path.is_ident(bound)
                 || supertrait.path.segments.len() == 3
                     && (supertrait.path.segments[0].ident == "std"
@@ -27,6 +45,18 @@ pub fn has_bound(supertraits: &Supertraits, bound: &InferredBound) -> bool {
     false
 }
 
+// Whether `path` names the same trait as `suffix`, allowing it to be more
+// qualified and ignoring generic arguments.
+fn ends_with(path: &Path, suffix: &Path) -> bool {
+    path.segments.len() >= suffix.segments.len()
+        && path
+            .segments
+            .iter()
+            .rev()
+            .zip(suffix.segments.iter().rev())
+            .all(|(segment, suffix)| segment.ident == suffix.ident)
+}
+
 impl InferredBound {
     fn as_str(&self) -> &str {
         match self {
@@ -0,0 +1,76 @@
+use proc_macro2::Span;
+use syn::visit_mut::{self, VisitMut};
+use syn::{parse_quote, Attribute, Block, Expr, Ident, Item, Stmt};
+
+pub fn has_track_caller(attrs: &[Attribute]) -> bool {
+    attrs
+        .iter()
+        .any(|attr| attr.path().is_ident("track_caller"))
+}
+
+// Name of the variable holding the location captured by the synchronous outer
+// fn, which is only visible to code generated by this macro.
+fn caller() -> Ident {
+    Ident::new("__caller", Span::mixed_site())
+}
+
+// Input:
+//     #[track_caller]
+//     async fn f(&self) {
+//         let location = Location::caller();
+//     }
+//
+// Output:
+//     #[track_caller]
+//     fn f<'async_trait>(&'async_trait self) -> Pin<Box<...>> {
+//         let __caller = ::core::panic::Location::caller();
+//         Box::pin(async move {
+//             let location = __caller;
+//         })
+//     }
+//
+// The async block is not #[track_caller] itself, so calls to Location::caller()
+// in the body refer to the location captured by the outer fn instead.
+pub fn replace_caller(block: &mut Block) {
+    ReplaceCaller.visit_block_mut(block);
+}
+
+pub fn capture_caller(block: &mut Block) {
+    let caller = caller();
+    let stmt: Stmt = parse_quote! {
+        let #caller = ::core::panic::Location::caller();
+    };
+    block.stmts.insert(0, stmt);
+}
+
+struct ReplaceCaller;
+
+impl VisitMut for ReplaceCaller {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        match expr {
+            Expr::Call(call) if call.args.is_empty() && is_location_caller(&call.func) => {
+                let caller = caller();
+                *expr = parse_quote!(#caller);
+            }
+            // Closures and nested async blocks have a location of their own.
+            Expr::Closure(_) | Expr::Async(_) => {}
+            _ => visit_mut::visit_expr_mut(self, expr),
+        }
+    }
+
+    fn visit_item_mut(&mut self, _: &mut Item) {
+        // Do not recurse into nested items.
+    }
+}
+
+fn is_location_caller(func: &Expr) -> bool {
+    let path = match func {
+        Expr::Path(func) if func.qself.is_none() => &func.path,
+        _ => return false,
+    };
+    let mut segments = path.segments.iter().rev();
+    match (segments.next(), segments.next()) {
+        (Some(method), Some(ty)) => method.ident == "caller" && ty.ident == "Location",
+        _ => false,
+    }
+}
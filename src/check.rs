@@ -0,0 +1,302 @@
+use crate::args::Args;
+use crate::expand::asyncify;
+use proc_macro2::{Span, TokenStream, TokenTree};
+use quote::{format_ident, quote, quote_spanned};
+use syn::{
+    parse_quote, Attribute, Generics, Ident, ImplItem, ItemImpl, ItemTrait, PathArguments,
+    ReturnType, Signature, TraitItem, Type,
+};
+
+// Input:
+//     #[async_trait(?Send)]
+//     pub trait Trait {
+//         async fn f(&self);
+//         fn g(&self);
+//     }
+//
+// Output:
+//     pub trait Trait {
+//         ...
+//
+//         #[deprecated(note = "...")]
+//         fn __async_trait_local_Trait_f(self: Pin<&Pin<&Self>>) where Self: Sized {}
+//
+//         #[deprecated(note = "...")]
+//         fn __async_trait_async_Trait_f(self: Pin<&Pin<&Self>>) where Self: Sized {}
+//
+//         #[deprecated(note = "...")]
+//         fn __async_trait_sync_Trait_g(self: Pin<&Pin<&Self>>) where Self: Sized {}
+//     }
+//
+// The markers record whether each method of the trait was lowered with or
+// without Send, taking its own #[async_trait(?Send)] into account, or not
+// lowered at all. An impl that disagrees refers to the corresponding marker in
+// `check`, and the deprecation note becomes the error message.
+//
+// Nothing but the check holds a Pin<&Pin<&T>>, so the markers do not show up
+// among the methods of any value of the implementing types.
+pub fn markers(input: &ItemTrait, args: &Args) -> Vec<TraitItem> {
+    let mut markers = Vec::new();
+    for item in &input.items {
+        if let TraitItem::Fn(method) = item {
+            let method_ident = &method.sig.ident;
+            if is_lowered(&method.sig) {
+                let local = is_local(&method.attrs, args);
+                let ident = mode_marker(&input.ident, method_ident, local);
+                let note = if local && !args.local {
+                    format!(
+                        "`{}` is declared with #[async_trait(?Send)] in the trait, so the impl needs #[async_trait(?Send)] for it too",
+                        method_ident,
+                    )
+                } else if local {
+                    "this trait is declared with #[async_trait(?Send)], so its impls need #[async_trait(?Send)] too".to_owned()
+                } else {
+                    "this trait is declared with #[async_trait], so its impls must not use #[async_trait(?Send)]".to_owned()
+                };
+                markers.push(marker(&method.attrs, &ident, &note));
+
+                let ident = async_marker(&input.ident, method_ident);
+                let note = format!(
+                    "`{}` is an async fn in the trait, so the impl needs `async fn {}` too",
+                    method_ident, method_ident,
+                );
+                markers.push(marker(&method.attrs, &ident, &note));
+            } else {
+                let ident = sync_marker(&input.ident, method_ident);
+                let note = format!(
+                    "`{}` is not an async fn in the trait, so the impl cannot use `async fn {}`",
+                    method_ident, method_ident,
+                );
+                markers.push(marker(&method.attrs, &ident, &note));
+            }
+        }
+    }
+    markers
+}
+
+fn marker(attrs: &[Attribute], ident: &Ident, note: &str) -> TraitItem {
+    let cfg_attrs = cfg_attrs(attrs);
+    parse_quote! {
+        #(#cfg_attrs)*
+        #[doc(hidden)]
+        #[deprecated(note = #note)]
+        #[allow(dead_code, non_snake_case)]
+        fn #ident(self: ::core::pin::Pin<&::core::pin::Pin<&Self>>)
+        where
+            Self: ::core::marker::Sized,
+        {
+        }
+    }
+}
+
+// Input:
+//     #[async_trait]
+//     impl<T> Trait<T> for Type<T> {
+//         async fn f(&self) {...}
+//     }
+//
+// Output:
+//     const _: () = {
+//         trait Fallback {
+//             fn __async_trait_local_Trait_f(&self) {}
+//             fn __async_trait_sync_Trait_f(&self) {}
+//         }
+//
+//         impl<T: ?Sized> Fallback for T {}
+//
+//         #[deny(deprecated)]
+//         async fn check<T>(value: Pin<&Pin<&impl Trait<T>>>) {
+//             value.__async_trait_local_Trait_f();
+//             value.__async_trait_sync_Trait_f();
+//         }
+//     };
+//
+// If the trait has the marker that contradicts the impl, the call resolves to
+// it. Otherwise autoref falls back to the method of the local Fallback trait,
+// which also covers traits not expanded by this macro. The markers are looked
+// up on a type parameter bounded by the trait path of the impl, so another
+// trait of the same name that happens to be in scope is not considered.
+//
+// A trait path without generic arguments does not refer to the generics of
+// the impl, which are then left out so that nothing in the impl header is type
+// checked a second time. If such a path leaves out the lifetimes of the trait,
+// an async fn argument reports the same error as the impl header, which rustc
+// then shows only once.
+pub fn check(input: &ItemImpl, args: &Args) -> Option<TokenStream> {
+    let (trait_path, _) = input.trait_.as_ref()?;
+    let trait_segment = trait_path.segments.last()?;
+
+    let mut generics = Generics::default();
+    if let PathArguments::AngleBracketed(_) | PathArguments::Parenthesized(_) =
+        trait_segment.arguments
+    {
+        // The check is a free function, where Self cannot be named, and the
+        // lifetimes of the impl would be reported a second time if they were
+        // not declared.
+        let where_clause = &input.generics.where_clause;
+        let header = quote!(#trait_path #where_clause);
+        if mentions_self(header.clone()) || mentions_undeclared_lifetime(header, &input.generics) {
+            return None;
+        }
+        generics = input.generics.clone();
+    }
+
+    let trait_ident = &trait_segment.ident;
+    let mut idents = Vec::new();
+    let mut cfgs = Vec::new();
+    for item in &input.items {
+        if let ImplItem::Fn(method) = item {
+            let method_ident = &method.sig.ident;
+            let markers = if is_lowered(&method.sig) {
+                let local = is_local(&method.attrs, args);
+                vec![
+                    mode_marker(trait_ident, method_ident, !local),
+                    sync_marker(trait_ident, method_ident),
+                ]
+            } else if cannot_be_boxed_future(&method.sig.output) {
+                vec![async_marker(trait_ident, method_ident)]
+            } else {
+                // The method may be returning a boxed future written out by
+                // hand, possibly through an alias, which is left for the
+                // compiler to compare against the trait.
+                Vec::new()
+            };
+            for ident in markers {
+                idents.push(Ident::new(&ident.to_string(), method_ident.span()));
+                cfgs.push(cfg_attrs(&method.attrs));
+            }
+        }
+    }
+    if idents.is_empty() {
+        return None;
+    }
+
+    let span = Span::call_site();
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+    Some(quote_spanned! {span=>
+        #[allow(dead_code, non_snake_case, clippy::unused_async)]
+        const _: () = {
+            trait __AsyncTraitFallback {
+                #(
+                    #(#cfgs)*
+                    fn #idents(&self) {}
+                )*
+            }
+
+            impl<T: ?::core::marker::Sized> __AsyncTraitFallback for T {}
+
+            #[deny(deprecated)]
+            async fn __async_trait_check #impl_generics (
+                value: ::core::pin::Pin<&::core::pin::Pin<&impl #trait_path>>,
+            ) #where_clause {
+                #(
+                    #(#cfgs)*
+                    value.#idents();
+                )*
+            }
+        };
+    })
+}
+
+fn cfg_attrs(attrs: &[Attribute]) -> Vec<&Attribute> {
+    attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("cfg"))
+        .collect()
+}
+
+fn mentions_self(tokens: TokenStream) -> bool {
+    tokens.into_iter().any(|tt| match tt {
+        TokenTree::Ident(ident) => ident == "Self",
+        TokenTree::Group(group) => mentions_self(group.stream()),
+        _ => false,
+    })
+}
+
+fn mentions_undeclared_lifetime(tokens: TokenStream, generics: &Generics) -> bool {
+    let mut tokens = tokens.into_iter().peekable();
+    while let Some(tt) = tokens.next() {
+        match tt {
+            TokenTree::Punct(punct) if punct.as_char() == '\'' => {
+                if let Some(TokenTree::Ident(ident)) = tokens.peek() {
+                    if ident != "static"
+                        && !generics
+                            .lifetimes()
+                            .any(|param| param.lifetime.ident == *ident)
+                    {
+                        return true;
+                    }
+                }
+            }
+            TokenTree::Group(group) if mentions_undeclared_lifetime(group.stream(), generics) => {
+                return true;
+            }
+            _ => {}
+        }
+    }
+    false
+}
+
+fn mode_marker(trait_ident: &Ident, method_ident: &Ident, local: bool) -> Ident {
+    let mode = if local { "local" } else { "send" };
+    format_ident!("__async_trait_{}_{}_{}", mode, trait_ident, method_ident)
+}
+
+fn async_marker(trait_ident: &Ident, method_ident: &Ident) -> Ident {
+    format_ident!("__async_trait_async_{}_{}", trait_ident, method_ident)
+}
+
+fn sync_marker(trait_ident: &Ident, method_ident: &Ident) -> Ident {
+    format_ident!("__async_trait_sync_{}_{}", trait_ident, method_ident)
+}
+
+// Whether the method is an async fn or a fn returning impl Future, both of
+// which get lowered to a boxed future.
+fn is_lowered(sig: &Signature) -> bool {
+    sig.asyncness.is_some() || asyncify(&mut sig.clone()).is_some()
+}
+
+// A return type that is spelled in a way no alias of Pin<Box<dyn Future>> can
+// be, such as a primitive or a reference. Any other path is given the benefit
+// of the doubt.
+fn cannot_be_boxed_future(output: &ReturnType) -> bool {
+    fn cannot_be_boxed_future(ty: &Type) -> bool {
+        match ty {
+            Type::Array(_)
+            | Type::FnPtr(_)
+            | Type::ImplTrait(_)
+            | Type::Never(_)
+            | Type::Ptr(_)
+            | Type::Reference(_)
+            | Type::Slice(_)
+            | Type::TraitObject(_)
+            | Type::Tuple(_) => true,
+            Type::Group(ty) => cannot_be_boxed_future(&ty.elem),
+            Type::Paren(ty) => cannot_be_boxed_future(&ty.elem),
+            Type::Path(ty) => {
+                const PRIMITIVES: &[&str] = &[
+                    "bool", "char", "f32", "f64", "i8", "i16", "i32", "i64", "i128", "isize",
+                    "str", "u8", "u16", "u32", "u64", "u128", "usize",
+                ];
+                ty.qself.is_none()
+                    && ty.path.segments.len() == 1
+                    && PRIMITIVES
+                        .iter()
+                        .any(|primitive| ty.path.segments[0].ident == primitive)
+            }
+            _ => false,
+        }
+    }
+
+    match output {
+        ReturnType::Default => true,
+        ReturnType::Type(_, ty) => cannot_be_boxed_future(ty),
+    }
+}
+
+// Whether the future of the method is lowered without Send, either by the
+// arguments of the enclosing trait or impl or by its own #[async_trait(?Send)].
+fn is_local(attrs: &[Attribute], args: &Args) -> bool {
+    args.with_method_attrs(&mut attrs.to_vec())
+        .map_or(args.local, |args| args.local)
+}
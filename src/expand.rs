@@ -1,5 +1,12 @@
+use crate::args::{find_async_trait_attr, Args, PointerKind};
+use crate::attrs::{reject_inner_attrs, route_attrs};
 use crate::bound::{has_bound, InferredBound, Supertraits};
+use crate::caller::{capture_caller, has_track_caller, replace_caller};
+use crate::check;
+use crate::forward;
+use crate::instrument::{create_span, instrument_future};
 use crate::lifetime::{AddLifetimeToImplTrait, CollectLifetimes};
+use crate::mock;
 use crate::parse::Item;
 use crate::receiver::{has_self_in_block, has_self_in_sig, mut_pat, ReplaceSelf};
 use crate::verbatim::VerbatimFn;
@@ -7,12 +14,14 @@ use proc_macro2::{Span, TokenStream};
 use quote::{format_ident, quote, quote_spanned, ToTokens};
 use std::collections::BTreeSet as Set;
 use std::mem;
+use syn::parse::{Error, Result};
 use syn::punctuated::Punctuated;
 use syn::visit_mut::{self, VisitMut};
 use syn::{
-    parse_quote, parse_quote_spanned, Attribute, Block, FnArg, GenericArgument, GenericParam,
-    Generics, Ident, ImplItem, Lifetime, LifetimeParam, Pat, PatIdent, PathArguments, Receiver,
-    ReceiverKind, ReturnType, Signature, Token, TraitItem, Type, TypeInfer, TypePath, WhereClause,
+    parse_quote, parse_quote_spanned, Attribute, Block, File, FnArg, GenericArgument, GenericParam,
+    Generics, Ident, ImplItem, ItemFn, ItemImpl, ItemTrait, Lifetime, LifetimeParam, Pat, PatIdent,
+    Path, PathArguments, Receiver, ReceiverKind, ReturnType, Signature, Token, TraitBound,
+    TraitItem, Type, TypeInfer, TypeParamBound, TypePath, WhereClause,
 };
 
 impl ToTokens for Item {
@@ -20,17 +29,21 @@ impl ToTokens for Item {
         match self {
             Item::Trait(item) => item.to_tokens(tokens),
             Item::Impl(item) => item.to_tokens(tokens),
+            Item::Fn(item) => item.to_tokens(tokens),
         }
     }
 }
 
 #[derive(Clone, Copy)]
-enum Context<'a> {
+pub enum Context<'a> {
     Trait {
+        name: &'a Ident,
         generics: &'a Generics,
         supertraits: &'a Supertraits,
     },
     Impl {
+        name: Option<&'a Ident>,
+        of_trait: bool,
         impl_generics: &'a Generics,
         associated_type_impl_traits: &'a Set<Ident>,
     },
@@ -51,77 +64,359 @@ impl Context<'_> {
             None
         })
     }
+
+    // Return position impl Trait only needs to be boxed in methods of a trait,
+    // where it would otherwise make the trait not dyn compatible.
+    fn boxes_impl_trait(&self) -> bool {
+        match self {
+            Context::Trait { .. } => true,
+            Context::Impl { of_trait, .. } => *of_trait,
+        }
+    }
+
+    // Name of the method qualified by the trait, or by the type for methods of
+    // an inherent impl, as in "Trait::method".
+    fn method_name(&self, sig: &Signature) -> String {
+        let name = match self {
+            Context::Trait { name, .. } => Some(*name),
+            Context::Impl { name, .. } => *name,
+        };
+        match name {
+            Some(name) => format!("{}::{}", name, sig.ident),
+            None => sig.ident.to_string(),
+        }
+    }
 }
 
-pub fn expand(input: &mut Item, is_local: bool) {
+pub fn expand(input: &mut Item, args: &Args) -> Result<TokenStream> {
+    if let Some(predicate) = &args.local_if {
+        return expand_local_if(input, predicate, args);
+    }
+
     match input {
-        Item::Trait(input) => {
-            let context = Context::Trait {
-                generics: &input.generics,
-                supertraits: &input.supertraits,
-            };
-            for inner in &mut input.items {
-                if let TraitItem::Fn(method) = inner {
-                    let sig = &mut method.sig;
-                    if sig.asyncness.is_some() {
-                        let block = &mut method.default;
-                        let mut has_self = has_self_in_sig(sig);
-                        if let Some(block) = block {
-                            has_self |= has_self_in_block(block);
-                            transform_block(context, sig, block);
-                            method.attrs.push(lint_suppress_with_body());
-                        } else {
-                            method.attrs.push(lint_suppress_without_body());
-                        }
-                        let has_default = method.default.is_some();
-                        transform_sig(context, sig, has_self, has_default, is_local);
-                    }
+        Item::Trait(input) => match (&args.dyn_trait, &args.variants) {
+            (Some(_), _) | (_, Some(_)) if !args.forward.is_empty() => {
+                let msg = "forward(...) cannot be combined with dyn = ... or variants(...)";
+                Err(Error::new_spanned(&args.forward[0], msg))
+            }
+            (Some(_), _) | (_, Some(_)) if args.mock.is_some() => {
+                let msg = "mock cannot be combined with dyn = ... or variants(...)";
+                Err(Error::new(Span::call_site(), msg))
+            }
+            (Some(dyn_trait), None) => forward::dyn_trait(input, dyn_trait, args),
+            (None, Some(variants)) => {
+                if args.local {
+                    let msg = "?Send cannot be combined with variants(...)";
+                    return Err(Error::new_spanned(&variants.local, msg));
                 }
+                forward::variants(input, variants, args)
             }
-        }
+            (Some(dyn_trait), Some(_)) => {
+                let msg = "dyn = ... cannot be combined with variants(...)";
+                Err(Error::new_spanned(dyn_trait, msg))
+            }
+            (None, None) => {
+                let original = input.clone();
+                expand_trait(input, args, false)?;
+                let forward = forward::forward_impls(&original, input, args)?;
+                let mock = mock::mock(&original, args)?;
+                input.items.extend(check::markers(&original, args));
+                Ok(quote!(#input #(#forward)* #mock))
+            }
+        },
         Item::Impl(input) => {
-            let mut associated_type_impl_traits = Set::new();
-            for inner in &input.items {
-                if let ImplItem::Type(assoc) = inner {
-                    if let Type::ImplTrait(_) = assoc.ty {
-                        associated_type_impl_traits.insert(assoc.ident.clone());
-                    }
+            reject_trait_args(args)?;
+            let check = check::check(input, args);
+            expand_impl(input, args)?;
+            Ok(quote!(#input #check))
+        }
+        Item::Fn(input) => {
+            reject_trait_args(args)?;
+            expand_fn(input, args)?;
+            Ok(quote!(#input))
+        }
+    }
+}
+
+// Input:
+//     #[async_trait(?Send if cfg(target_arch = "wasm32"))]
+//     pub trait Trait {...}
+//
+// Output:
+//     #[cfg(target_arch = "wasm32")]
+//     #[async_trait(?Send)]
+//     pub trait Trait {...}
+//
+//     #[cfg(not(target_arch = "wasm32"))]
+//     #[async_trait]
+//     pub trait Trait {...}
+fn expand_local_if(input: &mut Item, predicate: &TokenStream, args: &Args) -> Result<TokenStream> {
+    let mut expanded = TokenStream::new();
+    for (local, cfg) in [
+        (true, quote!(#[cfg(#predicate)])),
+        (false, quote!(#[cfg(not(#predicate))])),
+    ] {
+        let args = Args {
+            local,
+            local_if: None,
+            ..args.clone()
+        };
+        // Every item of the expansion, including any generated alongside a
+        // trait, goes under the cfg.
+        let items = syn::parse2::<File>(expand(&mut input.clone(), &args)?)?.items;
+        expanded.extend(quote!(#(#cfg #items)*));
+    }
+    Ok(expanded)
+}
+
+fn reject_trait_args(args: &Args) -> Result<()> {
+    if let Some(dyn_trait) = &args.dyn_trait {
+        let msg = "dyn = ... is only supported on a trait";
+        return Err(Error::new_spanned(dyn_trait, msg));
+    }
+    if let Some(variants) = &args.variants {
+        let msg = "variants(...) is only supported on a trait";
+        return Err(Error::new_spanned(&variants.send, msg));
+    }
+    if let Some(target) = args.forward.first() {
+        let msg = "forward(...) is only supported on a trait";
+        return Err(Error::new_spanned(target, msg));
+    }
+    if args.mock.is_some() {
+        let msg = "mock is only supported on a trait";
+        return Err(Error::new(Span::call_site(), msg));
+    }
+    Ok(())
+}
+
+// With `native` set, async fns are left as native async fn or lowered to
+// `impl Future + Send` instead of being boxed.
+pub fn expand_trait(input: &mut ItemTrait, args: &Args, native: bool) -> Result<()> {
+    let context = Context::Trait {
+        name: &input.ident,
+        generics: &input.generics,
+        supertraits: &input.supertraits,
+    };
+    for inner in &mut input.items {
+        if let TraitItem::Fn(method) = inner {
+            // The dyn-compatible twin puts them on its bridge method instead.
+            if method.sig.asyncness.is_some() && method.default.is_none() && !native {
+                reject_inner_attrs(&method.attrs)?;
+            }
+            let inner_attrs = route_attrs(&mut method.attrs)?;
+            let sig = &mut method.sig;
+            if sig.asyncness.is_some() {
+                let args = args.with_method_attrs(&mut method.attrs)?;
+                reject_borrowed_args(sig, &args)?;
+                if native && args.local {
+                    method.attrs.extend(inner_attrs);
+                    method.attrs.push(parse_quote!(#[allow(async_fn_in_trait)]));
+                    continue;
+                }
+                let block = &mut method.default;
+                let mut has_self = has_self_in_sig(sig);
+                if let Some(block) = block {
+                    has_self |= has_self_in_block(block);
+                    let track_caller = has_track_caller(&method.attrs);
+                    transform_block(
+                        context,
+                        sig,
+                        block,
+                        &args,
+                        !native,
+                        track_caller,
+                        &inner_attrs,
+                    );
+                    method.attrs.push(lint_suppress_with_body());
+                } else {
+                    method.attrs.push(lint_suppress_without_body());
+                }
+                let has_default = method.default.is_some();
+                if native {
+                    transform_sig_native(context, sig, has_self, has_default, &args);
+                } else {
+                    transform_sig(context, sig, has_self, has_default, &args);
+                }
+            } else if let Some(future) = (!native).then(|| asyncify(sig)).flatten() {
+                let args = args.with_method_attrs(&mut method.attrs)?;
+                let args = Args {
+                    sync: args.sync || future.sync,
+                    ..args
+                };
+                reject_borrowed_args(sig, &args)?;
+                let mut has_self = has_self_in_sig(sig);
+                if let Some(block) = &mut method.default {
+                    has_self |= has_self_in_block(block);
+                    method.attrs.push(lint_suppress_with_body());
+                } else {
+                    method.attrs.push(lint_suppress_without_body());
                 }
+                // The body is not moved into a future, so it is covered by the
+                // attributes of the method itself.
+                method.attrs.extend(inner_attrs);
+                let block = method.default.as_mut();
+                transform_fn_returning_future(context, sig, block, has_self, &future, &args);
+            } else {
+                reject_method_attrs(&method.attrs)?;
+                method.attrs.extend(inner_attrs);
             }
+        }
+    }
+    Ok(())
+}
 
-            let context = Context::Impl {
-                impl_generics: &input.generics,
-                associated_type_impl_traits: &associated_type_impl_traits,
-            };
-            for inner in &mut input.items {
-                match inner {
-                    ImplItem::Fn(method) if method.sig.asyncness.is_some() => {
-                        let sig = &mut method.sig;
-                        let block = &mut method.block;
-                        let has_self = has_self_in_sig(sig);
-                        transform_block(context, sig, block);
-                        transform_sig(context, sig, has_self, false, is_local);
-                        method.attrs.push(lint_suppress_with_body());
-                    }
-                    ImplItem::Verbatim(tokens) => {
-                        let mut method = match syn::parse2::<VerbatimFn>(tokens.clone()) {
-                            Ok(method) if method.sig.asyncness.is_some() => method,
-                            _ => continue,
-                        };
-                        let sig = &mut method.sig;
-                        let has_self = has_self_in_sig(sig);
-                        transform_sig(context, sig, has_self, false, is_local);
-                        method.attrs.push(lint_suppress_with_body());
-                        *tokens = quote!(#method);
-                    }
-                    _ => {}
+pub fn expand_impl(input: &mut ItemImpl, args: &Args) -> Result<()> {
+    let mut associated_type_impl_traits = Set::new();
+    for inner in &input.items {
+        if let ImplItem::Type(assoc) = inner {
+            if let Type::ImplTrait(_) = assoc.ty {
+                associated_type_impl_traits.insert(assoc.ident.clone());
+            }
+        }
+    }
+
+    let name = match &input.trait_ {
+        Some((path, _)) => Some(&path.segments.last().unwrap().ident),
+        None => match &*input.self_ty {
+            Type::Path(ty) => Some(&ty.path.segments.last().unwrap().ident),
+            _ => None,
+        },
+    };
+    let context = Context::Impl {
+        name,
+        of_trait: input.trait_.is_some(),
+        impl_generics: &input.generics,
+        associated_type_impl_traits: &associated_type_impl_traits,
+    };
+    for inner in &mut input.items {
+        match inner {
+            ImplItem::Fn(method) if method.sig.asyncness.is_some() => {
+                let inner_attrs = route_attrs(&mut method.attrs)?;
+                let args = args.with_method_attrs(&mut method.attrs)?;
+                reject_borrowed_args(&method.sig, &args)?;
+                let sig = &mut method.sig;
+                let block = &mut method.block;
+                let has_self = has_self_in_sig(sig);
+                let track_caller = has_track_caller(&method.attrs);
+                transform_block(context, sig, block, &args, true, track_caller, &inner_attrs);
+                transform_sig(context, sig, has_self, false, &args);
+                method.attrs.push(lint_suppress_with_body());
+            }
+            // An inherent method can return impl Future as it is.
+            ImplItem::Fn(method) => match context
+                .boxes_impl_trait()
+                .then(|| asyncify(&mut method.sig))
+                .flatten()
+            {
+                Some(future) => {
+                    let inner_attrs = route_attrs(&mut method.attrs)?;
+                    method.attrs.extend(inner_attrs);
+                    let args = args.with_method_attrs(&mut method.attrs)?;
+                    let args = Args {
+                        sync: args.sync || future.sync,
+                        ..args
+                    };
+                    reject_borrowed_args(&method.sig, &args)?;
+                    let sig = &mut method.sig;
+                    let block = Some(&mut method.block);
+                    let has_self = has_self_in_sig(sig);
+                    transform_fn_returning_future(context, sig, block, has_self, &future, &args);
+                    method.attrs.push(lint_suppress_with_body());
                 }
+                None => {
+                    let inner_attrs = route_attrs(&mut method.attrs)?;
+                    reject_method_attrs(&method.attrs)?;
+                    method.attrs.extend(inner_attrs);
+                }
+            },
+            ImplItem::Verbatim(tokens) => {
+                let mut method = match syn::parse2::<VerbatimFn>(tokens.clone()) {
+                    Ok(method) if method.sig.asyncness.is_some() => method,
+                    _ => continue,
+                };
+                reject_inner_attrs(&method.attrs)?;
+                route_attrs(&mut method.attrs)?;
+                let args = args.with_method_attrs(&mut method.attrs)?;
+                reject_borrowed_args(&method.sig, &args)?;
+                let sig = &mut method.sig;
+                let has_self = has_self_in_sig(sig);
+                transform_sig(context, sig, has_self, false, &args);
+                method.attrs.push(lint_suppress_with_body());
+                *tokens = quote!(#method);
             }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// A free async fn is treated like a method of an impl block without generics.
+fn expand_fn(input: &mut ItemFn, args: &Args) -> Result<()> {
+    let sig = &mut input.sig;
+    if sig.asyncness.is_none() {
+        let msg = "expected an async fn";
+        return Err(Error::new_spanned(sig.fn_token, msg));
+    }
+    let impl_generics = Generics::default();
+    let associated_type_impl_traits = Set::new();
+    let context = Context::Impl {
+        name: None,
+        of_trait: false,
+        impl_generics: &impl_generics,
+        associated_type_impl_traits: &associated_type_impl_traits,
+    };
+    let inner_attrs = route_attrs(&mut input.attrs)?;
+    let track_caller = has_track_caller(&input.attrs);
+    transform_block(
+        context,
+        sig,
+        &mut input.block,
+        args,
+        true,
+        track_caller,
+        &inner_attrs,
+    );
+    transform_sig(context, sig, false, false, args);
+    input.attrs.push(lint_suppress_with_body());
+    Ok(())
+}
+
+// A future that is 'static cannot hold on to anything borrowed from the
+// arguments, including the receiver.
+fn reject_borrowed_args(sig: &Signature, args: &Args) -> Result<()> {
+    if !args.static_future {
+        return Ok(());
+    }
+    for arg in &sig.inputs {
+        let mut lifetimes = CollectLifetimes::new();
+        match arg.clone() {
+            FnArg::Receiver(mut arg) => lifetimes.visit_receiver_mut(&mut arg),
+            FnArg::Typed(mut arg) => lifetimes.visit_type_mut(&mut arg.ty),
         }
+        if !lifetimes.elided.is_empty()
+            || lifetimes
+                .explicit
+                .iter()
+                .any(|lifetime| lifetime.ident != "static")
+        {
+            let msg = "a method returning a 'static future cannot take borrowed arguments";
+            return Err(Error::new_spanned(arg, msg));
+        }
+    }
+    Ok(())
+}
+
+fn reject_method_attrs(attrs: &[Attribute]) -> Result<()> {
+    match find_async_trait_attr(attrs) {
+        Some(attr) => Err(Error::new_spanned(
+            attr,
+            "#[async_trait(...)] on a method is only supported on async fn",
+        )),
+        None => Ok(()),
     }
 }
 
-fn lint_suppress_with_body() -> Attribute {
+pub fn lint_suppress_with_body() -> Attribute {
     parse_quote! {
         #[allow(
             elided_named_lifetimes,
@@ -133,12 +428,13 @@ fn lint_suppress_with_body() -> Attribute {
             clippy::shadow_same,
             clippy::type_complexity,
             clippy::type_repetition_in_bounds,
-            clippy::used_underscore_binding
+            clippy::used_underscore_binding,
+            clippy::useless_conversion
         )]
     }
 }
 
-fn lint_suppress_without_body() -> Attribute {
+pub fn lint_suppress_without_body() -> Attribute {
     parse_quote! {
         #[allow(
             elided_named_lifetimes,
@@ -161,18 +457,24 @@ fn lint_suppress_without_body() -> Attribute {
 //         'life1: 'async_trait,
 //         T: 'async_trait,
 //         Self: Sync + 'async_trait;
-fn transform_sig(
+pub fn transform_sig(
     context: Context,
     sig: &mut Signature,
     has_self: bool,
     has_default: bool,
-    is_local: bool,
+    args: &Args,
 ) {
     sig.fn_token.span = sig.asyncness.take().unwrap().span;
 
+    let bound = args.future_lifetime(Span::call_site());
     let (ret_arrow, ret) = match &sig.output {
         ReturnType::Default => (quote!(->), quote!(())),
-        ReturnType::Type(arrow, ret) => (quote!(#arrow), quote!(#ret)),
+        ReturnType::Type(arrow, ret) => {
+            let ret = boxed_impl_trait(ret, args)
+                .filter(|_| context.boxes_impl_trait())
+                .map_or_else(|| quote!(#ret), |boxed| quote!(#boxed));
+            (quote!(#arrow), ret)
+        }
     };
 
     let mut lifetimes = CollectLifetimes::new();
@@ -193,11 +495,12 @@ fn transform_sig(
                 };
                 if param.attrs.is_empty() {
                     let bounds = mem::take(&mut param.bounds);
+                    let bound = args.future_lifetime(span);
                     where_clause_or_default(&mut sig.generics.where_clause)
                         .predicates
-                        .push(parse_quote_spanned!(span=> #param_name: 'async_trait + #bounds));
+                        .push(parse_quote_spanned!(span=> #param_name: #bound + #bounds));
                 } else {
-                    param.bounds.push(parse_quote!('async_trait));
+                    param.bounds.push(parse_quote!(#bound));
                 }
             }
             GenericParam::Lifetime(param) => {
@@ -208,11 +511,12 @@ fn transform_sig(
                 };
                 if param.attrs.is_empty() {
                     let bounds = mem::take(&mut param.bounds);
+                    let bound = args.future_lifetime(span);
                     where_clause_or_default(&mut sig.generics.where_clause)
                         .predicates
-                        .push(parse_quote_spanned!(span=> #param: 'async_trait + #bounds));
+                        .push(parse_quote_spanned!(span=> #param: #bound + #bounds));
                 } else {
-                    param.bounds.push(parse_quote!('async_trait));
+                    param.bounds.push(parse_quote!(#bound));
                 }
             }
             GenericParam::Const(_) => {}
@@ -222,9 +526,10 @@ fn transform_sig(
     for param in context.lifetimes(&lifetimes.explicit) {
         let param = &param.lifetime;
         let span = param.span();
+        let bound = args.future_lifetime(span);
         where_clause_or_default(&mut sig.generics.where_clause)
             .predicates
-            .push(parse_quote_spanned!(span=> #param: 'async_trait));
+            .push(parse_quote_spanned!(span=> #param: #bound));
     }
 
     if sig.generics.lt_token.is_none() {
@@ -236,66 +541,368 @@ fn transform_sig(
 
     for elided in lifetimes.elided {
         sig.generics.params.push(parse_quote!(#elided));
+        let bound = args.future_lifetime(elided.span());
         where_clause_or_default(&mut sig.generics.where_clause)
             .predicates
-            .push(parse_quote_spanned!(elided.span()=> #elided: 'async_trait));
+            .push(parse_quote_spanned!(elided.span()=> #elided: #bound));
     }
 
-    sig.generics.params.push(parse_quote!('async_trait));
+    if !args.static_future {
+        sig.generics.params.push(parse_quote!('async_trait));
+    }
 
     if has_self {
-        let bounds: &[InferredBound] = if is_local {
-            &[]
-        } else if let Some(receiver) = sig.receiver() {
-            match &receiver.kind {
-                // &self
-                ReceiverKind::Reference(_ampersand, _lifetime, None::<Token![mut]>) => {
-                    &[InferredBound::Sync]
-                }
-                ReceiverKind::Typed(_colon, ty) => match ty.as_ref() {
-                    // self: &Self
-                    Type::Reference(ty) if ty.mutability.is_none() => &[InferredBound::Sync],
-                    // self: Arc<Self>
-                    Type::Path(ty)
-                        if {
-                            let segment = ty.path.segments.last().unwrap();
-                            segment.ident == "Arc"
-                                && match &segment.arguments {
-                                    PathArguments::AngleBracketed(arguments) => {
-                                        arguments.args.len() == 1
-                                            && match &arguments.args[0] {
-                                                GenericArgument::Type(Type::Path(arg)) => {
-                                                    arg.path.is_ident("Self")
-                                                }
-                                                _ => false,
-                                            }
-                                    }
-                                    _ => false,
-                                }
-                        } =>
-                    {
-                        &[InferredBound::Sync, InferredBound::Send]
+        let bounds = self_bounds(context, sig, has_default, args);
+        where_clause_or_default(&mut sig.generics.where_clause)
+            .predicates
+            .push(parse_quote! {
+                Self: #(#bounds +)* #bound
+            });
+    }
+
+    transform_args(sig);
+    for arg in &mut sig.inputs {
+        if let FnArg::Typed(arg) = arg {
+            AddLifetimeToImplTrait(bound.clone()).visit_type_mut(&mut arg.ty);
+        }
+    }
+
+    let send = if args.local {
+        None
+    } else {
+        Some(quote!(::core::marker::Send+))
+    };
+    let sync = if args.sync {
+        Some(quote!(::core::marker::Sync+))
+    } else {
+        None
+    };
+    let bounds = quote!(#send #sync #bound);
+    sig.output = match &args.future {
+        Some(future) => parse_quote!(#ret_arrow #future<#bound, #ret>),
+        None => {
+            let boxed = args.boxed(Span::call_site());
+            parse_quote! {
+                #ret_arrow ::core::pin::Pin<#boxed<
+                    dyn ::core::future::Future<Output = #ret> + #bounds
+                >>
+            }
+        }
+    };
+}
+
+// Input:
+//     async fn f(&self, x: &T) -> Ret;
+//
+// Output:
+//     fn f(&self, x: &T) -> impl Future<Output = Ret> + Send;
+//
+// Used for traits that keep native async fn alongside a boxed twin. Return
+// position impl Trait in traits captures every lifetime in scope, so there is
+// no need to name elided lifetimes.
+fn transform_sig_native(
+    context: Context,
+    sig: &mut Signature,
+    has_self: bool,
+    has_default: bool,
+    args: &Args,
+) {
+    sig.fn_token.span = sig.asyncness.take().unwrap().span;
+
+    let (ret_arrow, ret) = match &sig.output {
+        ReturnType::Default => (quote!(->), quote!(())),
+        ReturnType::Type(arrow, ret) => (quote!(#arrow), quote!(#ret)),
+    };
+
+    if has_self {
+        let bounds = self_bounds(context, sig, has_default, args);
+        if !bounds.is_empty() {
+            where_clause_or_default(&mut sig.generics.where_clause)
+                .predicates
+                .push(parse_quote! {
+                    Self: #(#bounds)+*
+                });
+        }
+    }
+
+    transform_args(sig);
+
+    let sync = if args.sync {
+        Some(quote!(+ ::core::marker::Sync))
+    } else {
+        None
+    };
+    let lifetime = if args.static_future {
+        Some(quote!(+ 'static))
+    } else {
+        None
+    };
+    sig.output = parse_quote! {
+        #ret_arrow impl ::core::future::Future<Output = #ret> + ::core::marker::Send #sync #lifetime
+    };
+}
+
+// Input:
+//     fn f(&self) -> impl Future<Output = Ret> + Send;
+//
+// Output:
+//     async fn f(&self) -> Ret;
+//
+// Returns None without touching the signature if it is not a non-async fn
+// returning impl Future.
+pub struct FutureReturn {
+    // Whether the future was declared Sync.
+    pub sync: bool,
+    // Path by which the signature named the Future trait.
+    pub path: Path,
+}
+
+pub fn asyncify(sig: &mut Signature) -> Option<FutureReturn> {
+    if sig.asyncness.is_some() {
+        return None;
+    }
+    let impl_trait = match &sig.output {
+        ReturnType::Type(_, ret) => match &**ret {
+            Type::ImplTrait(impl_trait) => impl_trait,
+            _ => return None,
+        },
+        ReturnType::Default => return None,
+    };
+
+    let mut output = None;
+    let mut path = None;
+    let mut sync = false;
+    for bound in &impl_trait.bounds {
+        if let TypeParamBound::Trait(bound) = bound {
+            let segment = bound.path.segments.last().unwrap();
+            if segment.ident == "Sync" {
+                sync = true;
+            } else if segment.ident == "Future" {
+                path = Some(bound.path.clone());
+                if let PathArguments::AngleBracketed(arguments) = &segment.arguments {
+                    for arg in &arguments.args {
+                        if let GenericArgument::AssocType(assoc) = arg {
+                            if assoc.ident == "Output" {
+                                output = Some(assoc.ty.clone());
+                            }
+                        }
                     }
-                    _ => &[InferredBound::Send],
-                },
-                _ => &[InferredBound::Send],
+                }
             }
-        } else {
-            &[InferredBound::Send]
-        };
+        }
+    }
 
-        let bounds = bounds.iter().filter(|bound| match context {
-            Context::Trait { supertraits, .. } => has_default && !has_bound(supertraits, bound),
-            Context::Impl { .. } => false,
+    let output = output?;
+    let path = path?;
+    let span = impl_trait.impl_token.span;
+    sig.asyncness = Some(Token![async](span));
+    if let ReturnType::Type(_, ret) = &mut sig.output {
+        **ret = output;
+    }
+    Some(FutureReturn { sync, path })
+}
+
+// Input:
+//     fn f(&self, x: X) -> impl Future<Output = Ret> + Send {
+//         let y = x.clone();
+//         async move { y }
+//     }
+//
+// Output:
+//     fn f<'life0, 'async_trait>(
+//         &'life0 self,
+//         x: X,
+//     ) -> Pin<Box<dyn Future<Output = Ret> + Send + 'async_trait>>
+//     where
+//         'life0: 'async_trait,
+//         Self: 'async_trait,
+//     {
+//         let __future = {
+//             let y = x.clone();
+//             async move { y }
+//         };
+//         Box::pin(__future)
+//     }
+//
+// The signature must have been through `asyncify` first. Unlike the body of an
+// async fn, this body runs when the method is called, and decides for itself
+// what the future captures.
+fn transform_fn_returning_future(
+    context: Context,
+    sig: &mut Signature,
+    block: Option<&mut Block>,
+    has_self: bool,
+    future: &FutureReturn,
+    args: &Args,
+) {
+    let original = sig.inputs.clone();
+    transform_sig(context, sig, has_self, block.is_some(), args);
+
+    // Name the Future trait the way the original signature did, so that an
+    // import of it does not become unused.
+    if args.future.is_none() {
+        struct RespellFuture<'a>(&'a Path);
+
+        impl VisitMut for RespellFuture<'_> {
+            fn visit_trait_bound_mut(&mut self, bound: &mut TraitBound) {
+                let segments = &bound.path.segments;
+                if bound.path.leading_colon.is_some()
+                    && segments.len() == 3
+                    && segments[0].ident == "core"
+                    && segments[1].ident == "future"
+                    && segments[2].ident == "Future"
+                {
+                    let arguments = mem::take(&mut bound.path.segments[2].arguments);
+                    bound.path = self.0.clone();
+                    bound.path.segments.last_mut().unwrap().arguments = arguments;
+                }
+            }
+        }
+
+        RespellFuture(&future.path).visit_return_type_mut(&mut sig.output);
+    }
+
+    // The body keeps using the original argument patterns.
+    for (arg, original) in sig.inputs.iter_mut().zip(original) {
+        match (arg, original) {
+            (FnArg::Receiver(receiver), FnArg::Receiver(original)) => {
+                receiver.mutability = original.mutability;
+            }
+            (FnArg::Typed(arg), FnArg::Typed(original)) => arg.pat = original.pat,
+            _ => unreachable!(),
+        }
+    }
+
+    if let Some(block) = block {
+        // The body is bound to a variable rather than passed straight to
+        // Box::pin, where rustc would suggest removing the braces of the fn.
+        let span = sig.fn_token.span;
+        let future = Ident::new("__future", Span::mixed_site());
+        let mut wrapped = quote!(#future);
+        if args.instrument.is_some() {
+            wrapped = instrument_future(wrapped);
+        }
+        if let Some(wrap) = &args.wrap {
+            let name = context.method_name(sig);
+            wrapped = quote_spanned!(span=> #wrap(#name, #wrapped));
+        }
+        let boxed_future = args.boxed_future(span, wrapped);
+        *block = parse_quote!({
+            let #future = #block;
+            #boxed_future
         });
+        if let Some(instrument) = &args.instrument {
+            create_span(instrument, &context.method_name(sig), sig, block);
+        }
+    }
+}
 
-        where_clause_or_default(&mut sig.generics.where_clause)
-            .predicates
-            .push(parse_quote! {
-                Self: #(#bounds +)* 'async_trait
-            });
+// Bounds that need to be placed on Self for the future returned by a default
+// method body to be Send.
+pub fn self_bounds(
+    context: Context,
+    sig: &Signature,
+    has_default: bool,
+    args: &Args,
+) -> Vec<&'static InferredBound> {
+    let (send, sync) = if args.local {
+        (false, false)
+    } else if let Some(receiver) = sig.receiver() {
+        match &receiver.kind {
+            // &self
+            ReceiverKind::Reference(_ampersand, _lifetime, None::<Token![mut]>) => (false, true),
+            // self: Arc<Self>, self: Pin<&Self>, ...
+            ReceiverKind::Typed(_colon, ty) => receiver_bounds(ty, false, args),
+            // &mut self, self
+            _ => (true, false),
+        }
+    } else {
+        (true, false)
+    };
+
+    // A future that is Sync needs everything it captures from self to be Sync.
+    let mut bounds = Vec::new();
+    if sync || args.sync {
+        bounds.push(&InferredBound::Sync);
+    }
+    if send {
+        bounds.push(&InferredBound::Send);
+    }
+
+    bounds
+        .into_iter()
+        .filter(|bound| match context {
+            Context::Trait { supertraits, .. } => {
+                has_default && !has_bound(supertraits, &args.implies, bound)
+            }
+            Context::Impl { .. } => false,
+        })
+        .collect()
+}
+
+// Whether Self needs to be Send and whether it needs to be Sync for a receiver
+// of the given type to be Send, or to be Sync if `sync` is set. Nothing makes
+// an Rc Send. A type of unknown shape is assumed to need the same bound as it
+// is required to satisfy.
+fn receiver_bounds(ty: &Type, sync: bool, args: &Args) -> (bool, bool) {
+    let (kind, inner) = match ty {
+        Type::Reference(ty) => {
+            let kind = if ty.mutability.is_some() {
+                PointerKind::Exclusive
+            } else {
+                PointerKind::Shared
+            };
+            (kind, &*ty.elem)
+        }
+        Type::Path(ty) if ty.qself.is_none() => match pointer(&ty.path, args) {
+            Some(pointer) => pointer,
+            None => return (!sync, sync),
+        },
+        Type::Paren(ty) => return receiver_bounds(&ty.elem, sync, args),
+        _ => return (!sync, sync),
+    };
+    match kind {
+        PointerKind::Shared => receiver_bounds(inner, true, args),
+        PointerKind::Exclusive | PointerKind::Owned => receiver_bounds(inner, sync, args),
+        PointerKind::Arc => {
+            let (send, sync) = receiver_bounds(inner, false, args);
+            let (also_send, also_sync) = receiver_bounds(inner, true, args);
+            (send || also_send, sync || also_sync)
+        }
+        PointerKind::Rc => (false, false),
     }
+}
+
+// The kind of smart pointer a path like `Arc<T>` refers to, along with T.
+fn pointer<'a>(path: &'a Path, args: &Args) -> Option<(PointerKind, &'a Type)> {
+    let segment = path.segments.last().unwrap();
+    let inner = match &segment.arguments {
+        PathArguments::AngleBracketed(arguments) => {
+            arguments.args.iter().find_map(|arg| match arg {
+                GenericArgument::Type(ty) => Some(ty),
+                _ => None,
+            })?
+        }
+        _ => return None,
+    };
+    let kind = match args
+        .pointers
+        .iter()
+        .find(|pointer| pointer.ident == segment.ident)
+    {
+        Some(pointer) => pointer.kind,
+        None if segment.ident == "Box" || segment.ident == "Pin" => PointerKind::Owned,
+        None if segment.ident == "Arc" => PointerKind::Arc,
+        None if segment.ident == "Rc" => PointerKind::Rc,
+        None => return None,
+    };
+    Some((kind, inner))
+}
 
+// Arguments other than references are moved into the async block by the
+// generated body, where any patterns get destructured. In the signature they
+// are replaced by a plain identifier.
+fn transform_args(sig: &mut Signature) {
     for (i, arg) in sig.inputs.iter_mut().enumerate() {
         match arg {
             FnArg::Receiver(receiver) => {
@@ -315,21 +922,9 @@ fn transform_sig(
                         arg.pat = parse_quote!(#m #positional);
                     }
                 }
-                AddLifetimeToImplTrait.visit_type_mut(&mut arg.ty);
             }
         }
     }
-
-    let bounds = if is_local {
-        quote!('async_trait)
-    } else {
-        quote!(::core::marker::Send + 'async_trait)
-    };
-    sig.output = parse_quote! {
-        #ret_arrow ::core::pin::Pin<Box<
-            dyn ::core::future::Future<Output = #ret> + #bounds
-        >>
-    };
 }
 
 // Input:
@@ -349,7 +944,15 @@ fn transform_sig(
 //
 //         ___ret
 //     })
-fn transform_block(context: Context, sig: &mut Signature, block: &mut Block) {
+fn transform_block(
+    context: Context,
+    sig: &mut Signature,
+    block: &mut Block,
+    args: &Args,
+    boxed: bool,
+    track_caller: bool,
+    inner_attrs: &[Attribute],
+) {
     let mut replace_self = false;
     let decls = sig
         .inputs
@@ -411,6 +1014,9 @@ fn transform_block(context: Context, sig: &mut Signature, block: &mut Block) {
     if replace_self {
         ReplaceSelf.visit_block_mut(block);
     }
+    if track_caller {
+        replace_caller(block);
+    }
 
     let let_ret = match &mut sig.output {
         ReturnType::Default => quote! {
@@ -418,7 +1024,23 @@ fn transform_block(context: Context, sig: &mut Signature, block: &mut Block) {
             let _: () = #block;
         },
         ReturnType::Type(_, ret) => {
-            if contains_associated_type_impl_trait(context, ret) {
+            if let Some(ret) =
+                boxed_impl_trait(ret, args).filter(|_| boxed && context.boxes_impl_trait())
+            {
+                // The original body is wrapped in its own async block so that
+                // any early return produces the unboxed value too.
+                let boxed = args.boxed(Span::call_site());
+                quote! {
+                    if let ::core::option::Option::Some(__ret) = ::core::option::Option::None::<#ret> {
+                        #[allow(unreachable_code)]
+                        return __ret;
+                    }
+                    #(#decls)*
+                    let __ret: #ret = #boxed::new(async move #block.await);
+                    #[allow(unreachable_code)]
+                    __ret
+                }
+            } else if contains_associated_type_impl_trait(context, ret) {
                 if decls.is_empty() {
                     let stmts = &block.stmts;
                     quote!(#(#stmts)*)
@@ -441,13 +1063,30 @@ fn transform_block(context: Context, sig: &mut Signature, block: &mut Block) {
             }
         }
     };
-    let box_pin = quote_spanned!(sig.asyncness.unwrap().span=>
-        Box::pin(async move { #let_ret })
-    );
-    block.stmts = parse_quote!(#box_pin);
+    let span = sig.asyncness.unwrap().span;
+    let mut async_block = quote_spanned!(span=> #(#inner_attrs)* async move { #let_ret });
+    if args.instrument.is_some() {
+        async_block = instrument_future(async_block);
+    }
+    if let Some(wrap) = &args.wrap {
+        let name = context.method_name(sig);
+        async_block = quote_spanned!(span=> #wrap(#name, #async_block));
+    }
+    block.stmts = if boxed {
+        let boxed_future = args.boxed_future(span, async_block);
+        parse_quote!(#boxed_future)
+    } else {
+        parse_quote!(#async_block)
+    };
+    if let Some(instrument) = &args.instrument {
+        create_span(instrument, &context.method_name(sig), sig, block);
+    }
+    if track_caller {
+        capture_caller(block);
+    }
 }
 
-fn positional_arg(i: usize, pat: &Pat) -> Ident {
+pub fn positional_arg(i: usize, pat: &Pat) -> Ident {
     let span = syn::spanned::Spanned::span(pat).resolved_at(Span::mixed_site());
     format_ident!("__arg{}", i, span = span)
 }
@@ -494,6 +1133,39 @@ fn where_clause_or_default(clause: &mut Option<WhereClause>) -> &mut WhereClause
     })
 }
 
+// Input:
+//     impl Iterator<Item = u8>
+//
+// Output:
+//     Box<dyn Iterator<Item = u8> + 'async_trait>
+//
+// Used in place of a return type that is impl Trait, which would otherwise make
+// the trait not dyn compatible.
+pub fn boxed_impl_trait(ret: &Type, args: &Args) -> Option<Type> {
+    let impl_trait = match ret {
+        Type::ImplTrait(impl_trait) => impl_trait,
+        _ => return None,
+    };
+    let mut has_lifetime = false;
+    let bounds = impl_trait.bounds.iter().filter(|bound| match bound {
+        TypeParamBound::Trait(_) => true,
+        TypeParamBound::Lifetime(_) => {
+            has_lifetime = true;
+            true
+        }
+        _ => false,
+    });
+    let bounds: Vec<&TypeParamBound> = bounds.collect();
+    let lifetime = if has_lifetime {
+        None
+    } else {
+        let lifetime = args.future_lifetime(Span::call_site());
+        Some(quote!(+ #lifetime))
+    };
+    let boxed = args.boxed(impl_trait.impl_token.span);
+    Some(parse_quote!(#boxed<dyn #(#bounds)+* #lifetime>))
+}
+
 fn replace_impl_trait_with_infer(ty: &mut Type) {
     struct ReplaceImplTraitWithInfer;
 
@@ -0,0 +1,674 @@
+use crate::args::{Args, Variants};
+use crate::attrs::route_attrs;
+use crate::bound::InferredBound;
+use crate::expand::{
+    asyncify, boxed_impl_trait, expand_trait, lint_suppress_with_body, lint_suppress_without_body,
+    positional_arg, self_bounds, transform_sig, Context,
+};
+use crate::receiver::{has_self_in_block, has_self_in_sig};
+use proc_macro2::{Span, TokenStream};
+use quote::quote;
+use syn::parse::{Error, Result};
+use syn::visit_mut::{self, VisitMut};
+use syn::{
+    parse_quote, Attribute, FnArg, GenericArgument, GenericParam, Generics, Ident, ItemTrait,
+    Lifetime, Pat, PathArguments, ReceiverKind, ReturnType, Signature, TraitItem, TraitItemConst,
+    TraitItemType, Type, TypeParamBound, TypePath, TypeReference, WherePredicate,
+};
+
+// Input:
+//     #[async_trait(dyn = DynTrait)]
+//     trait Trait {
+//         async fn f(&self) -> Ret;
+//     }
+//
+// Output:
+//     trait Trait {
+//         fn f(&self) -> impl Future<Output = Ret> + Send;
+//     }
+//
+//     trait DynTrait {
+//         fn f<'life0, 'async_trait>(
+//             &'life0 self,
+//         ) -> Pin<Box<dyn Future<Output = Ret> + Send + 'async_trait>>
+//         where
+//             'life0: 'async_trait,
+//             Self: 'async_trait;
+//     }
+//
+//     impl<__T: ?Sized + Trait> DynTrait for __T {
+//         fn f<'life0, 'async_trait>(
+//             &'life0 self,
+//         ) -> Pin<Box<dyn Future<Output = Ret> + Send + 'async_trait>>
+//         where
+//             'life0: 'async_trait,
+//             Self: 'async_trait,
+//         {
+//             Box::pin(<__T as Trait>::f(self))
+//         }
+//     }
+pub fn dyn_trait(input: &mut ItemTrait, dyn_trait: &Ident, args: &Args) -> Result<TokenStream> {
+    let ident = &input.ident;
+    let self_ty = Ident::new("__T", Span::call_site());
+    let (_, ty_generics, _) = input.generics.split_for_impl();
+    let trait_path = quote!(<#self_ty as #ident #ty_generics>);
+
+    let mut erased = input.clone();
+    erased.ident = dyn_trait.clone();
+    erased.attrs = cfg_attrs(&input.attrs);
+    let doc = format!(
+        "Dyn-compatible version of [`{}`], implemented for every type that implements it.",
+        ident,
+    );
+    erased.attrs.push(parse_quote!(#[doc = #doc]));
+    erased.items.clear();
+
+    let generics = blanket_generics(input, &self_ty, ident);
+    let context = Context::Trait {
+        name: &input.ident,
+        generics: &input.generics,
+        supertraits: &input.supertraits,
+    };
+
+    let mut bridge_items = Vec::new();
+    for item in &input.items {
+        match item {
+            TraitItem::Fn(method) => {
+                let mut decl = method.clone();
+                decl.default = None;
+                let inner_attrs = route_attrs(&mut decl.attrs)?;
+                decl.semi_token = Some(Default::default());
+                let call_args = forward_args(&mut decl.sig);
+                let method_ident = &decl.sig.ident;
+                let turbofish = turbofish(&decl.sig);
+                let call = quote!(#trait_path::#method_ident #turbofish(#(#call_args),*));
+                let mut attrs = cfg_attrs(&method.attrs);
+                // The body of the bridge method forwards to the trait method
+                // without an async block of its own, so the content of
+                // #[async_trait::inner(...)] goes on the method.
+                attrs.extend(inner_attrs);
+                let future_return = asyncify(&mut decl.sig);
+                let body = if decl.sig.asyncness.is_some() {
+                    let mut args = args.with_method_attrs(&mut decl.attrs)?;
+                    if let Some(future) = &future_return {
+                        args.sync |= future.sync;
+                    }
+                    // The native future captures Self and every generic
+                    // parameter of the trait.
+                    let has_default = method.default.is_some();
+                    transform_sig(context, &mut decl.sig, true, has_default, &args);
+                    let bound = args.future_lifetime(Span::call_site());
+                    let where_clause = decl.sig.generics.make_where_clause();
+                    for param in &input.generics.params {
+                        match param {
+                            GenericParam::Type(param) => {
+                                let param = &param.ident;
+                                where_clause.predicates.push(parse_quote!(#param: #bound));
+                            }
+                            GenericParam::Lifetime(param) => {
+                                let param = &param.lifetime;
+                                where_clause.predicates.push(parse_quote!(#param: #bound));
+                            }
+                            GenericParam::Const(_) => {}
+                        }
+                    }
+                    decl.attrs.push(lint_suppress_without_body());
+                    attrs.push(lint_suppress_with_body());
+                    let future = match (&method.sig.output, &future_return) {
+                        (ReturnType::Type(_, ret), None) => match boxed_impl_trait(ret, &args) {
+                            Some(ret) => {
+                                let boxed = args.boxed(Span::call_site());
+                                quote! {{
+                                    let __fut = #call;
+                                    async move {
+                                        let __ret: #ret = #boxed::new(__fut.await);
+                                        __ret
+                                    }
+                                }}
+                            }
+                            None => call,
+                        },
+                        _ => call,
+                    };
+                    args.boxed_future(Span::call_site(), future)
+                } else {
+                    call
+                };
+                let sig = &decl.sig;
+                bridge_items.push(quote! {
+                    #(#attrs)*
+                    #sig {
+                        #body
+                    }
+                });
+                erased.items.push(TraitItem::Fn(decl));
+            }
+            TraitItem::Type(assoc) => {
+                let mut decl = assoc.clone();
+                decl.default = None;
+                erased.items.push(TraitItem::Type(decl));
+
+                bridge_items.push(forward_type(assoc, &trait_path));
+            }
+            // Associated consts would make the trait not dyn compatible, so
+            // they are left out.
+            TraitItem::Const(_) => {}
+            _ => {
+                let msg = "unsupported item in a trait with #[async_trait(dyn = ...)]";
+                return Err(Error::new_spanned(item, msg));
+            }
+        }
+    }
+
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+    let attrs = cfg_attrs(&input.attrs);
+    let unsafety = &input.unsafety;
+    let bridge = quote! {
+        #(#attrs)*
+        #unsafety impl #impl_generics #dyn_trait #ty_generics for #self_ty #where_clause {
+            #(#bridge_items)*
+        }
+    };
+
+    expand_trait(input, args, true)?;
+    Ok(quote! {
+        #input
+        #erased
+        #bridge
+    })
+}
+
+// Input:
+//     #[async_trait(variants(Send = SendTrait, Local = Trait))]
+//     trait Trait {
+//         async fn f(&self) -> Ret;
+//     }
+//
+// Output:
+//     #[async_trait]
+//     trait SendTrait {
+//         async fn f(&self) -> Ret;
+//     }
+//
+//     #[async_trait(?Send)]
+//     trait Trait {
+//         async fn f(&self) -> Ret;
+//     }
+//
+//     impl<__T: ?Sized + SendTrait> Trait for __T {
+//         fn f<'life0, 'async_trait>(
+//             &'life0 self,
+//         ) -> Pin<Box<dyn Future<Output = Ret> + 'async_trait>>
+//         where
+//             'life0: 'async_trait,
+//             Self: 'async_trait,
+//         {
+//             <__T as SendTrait>::f(self)
+//         }
+//     }
+pub fn variants(input: &ItemTrait, variants: &Variants, args: &Args) -> Result<TokenStream> {
+    let send_ident = &variants.send;
+    let local_ident = &variants.local;
+    let self_ty = Ident::new("__T", Span::call_site());
+    let (_, ty_generics, _) = input.generics.split_for_impl();
+    let trait_path = quote!(<#self_ty as #send_ident #ty_generics>);
+
+    let bounds = default_self_bounds(input, args)?;
+
+    let mut send = input.clone();
+    send.ident = send_ident.clone();
+    expand_trait(&mut send, args, false)?;
+
+    let mut local = input.clone();
+    local.ident = local_ident.clone();
+    let local_args = Args {
+        local: true,
+        ..args.clone()
+    };
+    expand_trait(&mut local, &local_args, false)?;
+
+    let mut bridge_items = Vec::new();
+    for (item, lowered) in input.items.iter().zip(&local.items) {
+        match (item, lowered) {
+            (TraitItem::Fn(method), TraitItem::Fn(lowered)) => {
+                let mut sig = lowered.sig.clone();
+                let call_args = forward_args(&mut sig);
+                let method_ident = &sig.ident;
+                let turbofish = turbofish(&method.sig);
+                let mut attrs = cfg_attrs(&method.attrs);
+                if method.sig.asyncness.is_some() {
+                    attrs.push(lint_suppress_with_body());
+                }
+                bridge_items.push(quote! {
+                    #(#attrs)*
+                    #sig {
+                        #trait_path::#method_ident #turbofish(#(#call_args),*)
+                    }
+                });
+            }
+            (TraitItem::Type(assoc), _) => bridge_items.push(forward_type(assoc, &trait_path)),
+            (TraitItem::Const(konst), _) => bridge_items.push(forward_const(konst, &trait_path)),
+            _ => {
+                let msg = "unsupported item in a trait with #[async_trait(variants(...))]";
+                return Err(Error::new_spanned(item, msg));
+            }
+        }
+    }
+
+    let mut generics = blanket_generics(input, &self_ty, send_ident);
+    if !bounds.is_empty() {
+        generics
+            .make_where_clause()
+            .predicates
+            .push(parse_quote!(#self_ty: #(#bounds)+*));
+    }
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+    let attrs = cfg_attrs(&input.attrs);
+    let unsafety = &input.unsafety;
+    Ok(quote! {
+        #send
+        #local
+
+        #(#attrs)*
+        #unsafety impl #impl_generics #local_ident #ty_generics for #self_ty #where_clause {
+            #(#bridge_items)*
+        }
+    })
+}
+
+// Input:
+//     #[async_trait(forward(Box, Ref))]
+//     trait Trait {
+//         async fn f(&self) -> Ret;
+//     }
+//
+// Output:
+//     impl<__T: ?Sized + Trait> Trait for Box<__T> {
+//         fn f<'life0, 'async_trait>(
+//             &'life0 self,
+//         ) -> Pin<Box<dyn Future<Output = Ret> + Send + 'async_trait>>
+//         where
+//             'life0: 'async_trait,
+//             Self: 'async_trait,
+//         {
+//             <__T as Trait>::f(&**self)
+//         }
+//     }
+//
+//     impl<'__a, __T: ?Sized + Trait> Trait for &'__a __T {
+//         ...
+//     }
+//
+// The `expanded` trait is the result of expanding `original` as usual.
+pub fn forward_impls(
+    original: &ItemTrait,
+    expanded: &ItemTrait,
+    args: &Args,
+) -> Result<Vec<TokenStream>> {
+    let ident = &original.ident;
+    let self_ty = Ident::new("__T", Span::call_site());
+    let lifetime = Lifetime::new("'__a", Span::call_site());
+    let (_, ty_generics, _) = original.generics.split_for_impl();
+    let trait_path = quote!(<#self_ty as #ident #ty_generics>);
+    let bounds = default_self_bounds(original, args)?;
+
+    let mut impls = Vec::new();
+    for target in &args.forward {
+        let mut sized = false;
+        let mut items = Vec::new();
+        for (item, lowered) in original.items.iter().zip(&expanded.items) {
+            match (item, lowered) {
+                (TraitItem::Fn(method), TraitItem::Fn(lowered)) => {
+                    let mut visitor = HasSelfType(false);
+                    for arg in &method.sig.inputs {
+                        if let FnArg::Typed(arg) = arg {
+                            visitor.visit_type_mut(&mut arg.ty.clone());
+                        }
+                    }
+                    if let ReturnType::Type(_, ret) = &method.sig.output {
+                        visitor.visit_type_mut(&mut ret.clone());
+                    }
+                    if visitor.0 {
+                        let msg = format!(
+                            "cannot forward a method that uses Self by value through {}",
+                            target
+                        );
+                        return Err(Error::new_spanned(&method.sig, msg));
+                    }
+
+                    let mut sig = lowered.sig.clone();
+                    let mut call_args = forward_args(&mut sig);
+                    if let Some(receiver) = method.sig.receiver() {
+                        let kind = receiver_kind(receiver);
+                        sized |= target == "Box" && kind == Receiver::Value;
+                        call_args[0] = match (target.to_string().as_str(), kind) {
+                            (_, Receiver::Ref) => quote!(&**self),
+                            ("Box" | "RefMut", Receiver::RefMut) => quote!(&mut **self),
+                            ("Box", Receiver::Value | Receiver::Box) => quote!(*self),
+                            ("Arc", Receiver::Arc) => quote!(::core::clone::Clone::clone(&*self)),
+                            _ => {
+                                let msg = format!(
+                                    "cannot forward a method with this receiver through {}",
+                                    target
+                                );
+                                return Err(Error::new_spanned(receiver, msg));
+                            }
+                        };
+                    }
+                    let method_ident = &sig.ident;
+                    let turbofish = turbofish(&method.sig);
+                    let mut attrs = cfg_attrs(&method.attrs);
+                    if method.sig.asyncness.is_some() {
+                        attrs.push(lint_suppress_with_body());
+                    }
+                    items.push(quote! {
+                        #(#attrs)*
+                        #sig {
+                            #trait_path::#method_ident #turbofish(#(#call_args),*)
+                        }
+                    });
+                }
+                (TraitItem::Type(assoc), _) => items.push(forward_type(assoc, &trait_path)),
+                (TraitItem::Const(konst), _) => items.push(forward_const(konst, &trait_path)),
+                _ => {
+                    let msg = "unsupported item in a trait with #[async_trait(forward(...))]";
+                    return Err(Error::new_spanned(item, msg));
+                }
+            }
+        }
+
+        let mut generics = original.generics.clone();
+        let maybe_sized = if sized { None } else { Some(quote!(?Sized+)) };
+        generics
+            .params
+            .push(parse_quote!(#self_ty: #maybe_sized #ident #ty_generics));
+        if !bounds.is_empty() {
+            generics
+                .make_where_clause()
+                .predicates
+                .push(parse_quote!(#self_ty: #(#bounds)+*));
+        }
+        let for_ty = match target.to_string().as_str() {
+            "Box" => {
+                let boxed = args.boxed(Span::call_site());
+                quote!(#boxed<#self_ty>)
+            }
+            "Arc" => {
+                let alloc = args.alloc(Span::call_site());
+                quote!(#alloc::sync::Arc<#self_ty>)
+            }
+            "Ref" => quote!(&#lifetime #self_ty),
+            "RefMut" => quote!(&#lifetime mut #self_ty),
+            _ => unreachable!(),
+        };
+        if target == "Ref" || target == "RefMut" {
+            generics.params.insert(0, parse_quote!(#lifetime));
+        }
+
+        let (impl_generics, _, where_clause) = generics.split_for_impl();
+        let attrs = cfg_attrs(&original.attrs);
+        let unsafety = &original.unsafety;
+        impls.push(quote! {
+            #(#attrs)*
+            #unsafety impl #impl_generics #ident #ty_generics for #for_ty #where_clause {
+                #(#items)*
+            }
+        });
+    }
+    Ok(impls)
+}
+
+#[derive(PartialEq)]
+enum Receiver {
+    Ref,
+    RefMut,
+    Value,
+    Box,
+    Arc,
+    Other,
+}
+
+fn receiver_kind(receiver: &syn::Receiver) -> Receiver {
+    match &receiver.kind {
+        ReceiverKind::Reference(_ampersand, _lifetime, None) => Receiver::Ref,
+        ReceiverKind::Reference(_ampersand, _lifetime, Some(_mut)) => Receiver::RefMut,
+        ReceiverKind::Value => Receiver::Value,
+        ReceiverKind::Typed(_colon, ty) => match &**ty {
+            Type::Reference(ty) if is_self(&ty.elem) => match ty.mutability {
+                None => Receiver::Ref,
+                Some(_) => Receiver::RefMut,
+            },
+            Type::Path(ty) if ty.qself.is_none() => {
+                let segment = ty.path.segments.last().unwrap();
+                let wraps_self = match &segment.arguments {
+                    PathArguments::AngleBracketed(arguments) => {
+                        arguments.args.len() == 1
+                            && match &arguments.args[0] {
+                                GenericArgument::Type(arg) => is_self(arg),
+                                _ => false,
+                            }
+                    }
+                    _ => false,
+                };
+                if is_self(&Type::Path(ty.clone())) {
+                    Receiver::Value
+                } else if wraps_self && segment.ident == "Box" {
+                    Receiver::Box
+                } else if wraps_self && segment.ident == "Arc" {
+                    Receiver::Arc
+                } else {
+                    Receiver::Other
+                }
+            }
+            _ => Receiver::Other,
+        },
+        _ => Receiver::Other,
+    }
+}
+
+fn is_self(ty: &Type) -> bool {
+    match ty {
+        Type::Path(ty) => ty.qself.is_none() && ty.path.is_ident("Self"),
+        _ => false,
+    }
+}
+
+// Bounds that default method bodies place on Self, which a blanket impl needs
+// to satisfy in order to forward to them.
+fn default_self_bounds(input: &ItemTrait, args: &Args) -> Result<Vec<InferredBound>> {
+    let context = Context::Trait {
+        name: &input.ident,
+        generics: &input.generics,
+        supertraits: &input.supertraits,
+    };
+    let mut needs_send = false;
+    let mut needs_sync = false;
+    for item in &input.items {
+        if let TraitItem::Fn(method) = item {
+            if let (Some(_), Some(block)) = (&method.sig.asyncness, &method.default) {
+                let args = args.with_method_attrs(&mut method.attrs.clone())?;
+                let has_self = has_self_in_sig(&mut method.sig.clone())
+                    || has_self_in_block(&mut block.clone());
+                if has_self {
+                    for bound in self_bounds(context, &method.sig, true, &args) {
+                        match bound {
+                            InferredBound::Send => needs_send = true,
+                            InferredBound::Sync => needs_sync = true,
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    let mut bounds = Vec::new();
+    if needs_send {
+        bounds.push(InferredBound::Send);
+    }
+    if needs_sync {
+        bounds.push(InferredBound::Sync);
+    }
+    Ok(bounds)
+}
+
+// Generics of a blanket impl over every type implementing the given trait.
+fn blanket_generics(input: &ItemTrait, self_ty: &Ident, bound: &Ident) -> Generics {
+    let sized = input.items.iter().any(|item| match item {
+        TraitItem::Fn(method) => needs_sized(&method.sig),
+        _ => false,
+    });
+    let (_, ty_generics, _) = input.generics.split_for_impl();
+    let maybe_sized = if sized { None } else { Some(quote!(?Sized+)) };
+    let mut generics = input.generics.clone();
+    generics
+        .params
+        .push(parse_quote!(#self_ty: #maybe_sized #bound #ty_generics));
+    generics
+}
+
+fn forward_type(assoc: &TraitItemType, trait_path: &TokenStream) -> TokenStream {
+    let attrs = cfg_attrs(&assoc.attrs);
+    let assoc_ident = &assoc.ident;
+    let (impl_generics, ty_generics, where_clause) = assoc.generics.split_for_impl();
+    quote! {
+        #(#attrs)*
+        type #assoc_ident #impl_generics = #trait_path::#assoc_ident #ty_generics
+        #where_clause;
+    }
+}
+
+fn forward_const(konst: &TraitItemConst, trait_path: &TokenStream) -> TokenStream {
+    let attrs = cfg_attrs(&konst.attrs);
+    let const_ident = &konst.ident;
+    let ty = &konst.ty;
+    quote! {
+        #(#attrs)*
+        const #const_ident: #ty = #trait_path::#const_ident;
+    }
+}
+
+fn cfg_attrs(attrs: &[Attribute]) -> Vec<Attribute> {
+    attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("cfg"))
+        .cloned()
+        .collect()
+}
+
+// Give every argument a plain identifier so that it can be passed along to the
+// function being forwarded to, and return the list of arguments for the call.
+pub fn forward_args(sig: &mut Signature) -> Vec<TokenStream> {
+    sig.inputs
+        .iter_mut()
+        .enumerate()
+        .map(|(i, arg)| match arg {
+            FnArg::Receiver(receiver) => {
+                receiver.mutability = None;
+                let self_token = receiver.self_token;
+                quote!(#self_token)
+            }
+            FnArg::Typed(arg) => {
+                let ident = match &*arg.pat {
+                    Pat::Ident(pat) => pat.ident.clone(),
+                    pat => positional_arg(i, pat),
+                };
+                arg.pat = parse_quote!(#ident);
+                let attrs = cfg_attrs(&arg.attrs);
+                quote!(#(#attrs)* #ident)
+            }
+        })
+        .collect()
+}
+
+// Explicit type and const arguments for the call, which may be needed when a
+// generic parameter does not appear in the argument types. Not possible if
+// any argument uses impl Trait.
+fn turbofish(sig: &Signature) -> TokenStream {
+    let mut has_impl_trait = false;
+    for arg in &sig.inputs {
+        if let FnArg::Typed(arg) = arg {
+            let mut visitor = HasImplTrait(false);
+            visitor.visit_type_mut(&mut arg.ty.clone());
+            has_impl_trait |= visitor.0;
+        }
+    }
+
+    let params = sig.generics.params.iter().filter_map(|param| match param {
+        GenericParam::Type(param) => Some(&param.ident),
+        GenericParam::Const(param) => Some(&param.ident),
+        GenericParam::Lifetime(_) => None,
+    });
+    let params: Vec<&Ident> = params.collect();
+    if has_impl_trait || params.is_empty() {
+        TokenStream::new()
+    } else {
+        quote!(::<#(#params),*>)
+    }
+}
+
+struct HasImplTrait(bool);
+
+impl VisitMut for HasImplTrait {
+    fn visit_type_mut(&mut self, ty: &mut Type) {
+        if let Type::ImplTrait(_) = ty {
+            self.0 = true;
+        }
+        visit_mut::visit_type_mut(self, ty);
+    }
+}
+
+// Whether forwarding this method requires Self to be Sized, because Self is
+// passed or returned by value somewhere in the signature.
+fn needs_sized(sig: &Signature) -> bool {
+    if let Some(where_clause) = &sig.generics.where_clause {
+        for predicate in &where_clause.predicates {
+            if let WherePredicate::Type(predicate) = predicate {
+                if let Type::Path(ty) = &predicate.bounded_ty {
+                    if ty.path.is_ident("Self")
+                        && predicate.bounds.iter().any(|bound| match bound {
+                            TypeParamBound::Trait(bound) => bound.path.is_ident("Sized"),
+                            _ => false,
+                        })
+                    {
+                        return false;
+                    }
+                }
+            }
+        }
+    }
+
+    let mut visitor = HasSelfType(false);
+    for arg in &sig.inputs {
+        match arg {
+            FnArg::Receiver(receiver) => match &receiver.kind {
+                ReceiverKind::Value => return true,
+                ReceiverKind::Typed(_colon, ty) => {
+                    if let Type::Path(ty) = &**ty {
+                        if ty.path.is_ident("Self") {
+                            return true;
+                        }
+                    }
+                }
+                _ => {}
+            },
+            FnArg::Typed(arg) => visitor.visit_type_mut(&mut arg.ty.clone()),
+        }
+    }
+    if let ReturnType::Type(_, ret) = &sig.output {
+        visitor.visit_type_mut(&mut ret.clone());
+    }
+    visitor.0
+}
+
+struct HasSelfType(bool);
+
+impl VisitMut for HasSelfType {
+    fn visit_type_path_mut(&mut self, ty: &mut TypePath) {
+        self.0 |= ty.qself.is_none() && ty.path.is_ident("Self");
+        visit_mut::visit_type_path_mut(self, ty);
+    }
+
+    fn visit_type_reference_mut(&mut self, _ty: &mut TypeReference) {
+        // Self behind a reference does not need to be Sized.
+    }
+}
@@ -0,0 +1,59 @@
+use crate::args::Instrument;
+use proc_macro2::{Span, TokenStream};
+use quote::quote;
+use syn::{parse_quote, Block, FnArg, Ident, Pat, Signature, Stmt};
+
+// Input:
+//     #[async_trait(instrument)]
+//     async fn f(&self, x: u8) {...}
+//
+// Output:
+//     fn f<'async_trait>(&'async_trait self, x: u8) -> Pin<Box<...>> {
+//         let __span = ::tracing::span!(::tracing::Level::INFO, "Trait::f", x = ...);
+//         Box::pin(::tracing::Instrument::instrument(async move {...}, __span))
+//     }
+//
+// The span is created when the method is called, before the arguments are
+// moved into the future, and entered every time the future is polled.
+pub fn instrument_future(future: TokenStream) -> TokenStream {
+    let span = span_ident();
+    quote! {
+        ::tracing::Instrument::instrument(#future, #span)
+    }
+}
+
+pub fn create_span(instrument: &Instrument, name: &str, sig: &Signature, block: &mut Block) {
+    let span = span_ident();
+    let level = &instrument.level;
+    let fields = fields(instrument, sig);
+    let stmt: Stmt = parse_quote! {
+        let #span = ::tracing::span!(
+            ::tracing::Level::#level,
+            #name,
+            #(#fields = ::tracing::field::debug(&#fields),)*
+        );
+    };
+    block.stmts.insert(0, stmt);
+}
+
+fn span_ident() -> Ident {
+    Ident::new("__span", Span::mixed_site())
+}
+
+// Arguments recorded as fields of the span: every argument bound to a plain
+// identifier, except for self and the ones listed in skip(...).
+fn fields<'a>(instrument: &'a Instrument, sig: &'a Signature) -> Vec<&'a Ident> {
+    sig.inputs
+        .iter()
+        .filter_map(|arg| match arg {
+            FnArg::Typed(arg) if !arg.attrs.iter().any(|attr| attr.path().is_ident("cfg")) => {
+                match &*arg.pat {
+                    Pat::Ident(pat) => Some(&pat.ident),
+                    _ => None,
+                }
+            }
+            _ => None,
+        })
+        .filter(|ident| !instrument.skip.contains(ident))
+        .collect()
+}
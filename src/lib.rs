@@ -156,6 +156,40 @@
 //! # };
 //! ```
 //!
+//! The bound on Self follows from what the receiver needs in order for the
+//! future to be Send: `&self` and `self: Pin<&Self>` need `Self: Sync`, `self:
+//! &Arc<Self>` needs both `Send` and `Sync`, and `&mut self` or `self:
+//! Box<Self>` need `Self: Send`. A smart pointer of your own can be declared to
+//! behave like one of `&`, `&mut`, `Box`, `Arc` or `Rc` by writing
+//! `#[async_trait(receiver(Guard = &))]` on the trait and its impls.
+//!
+//! The bound is left out if a supertrait already provides it. Supertraits
+//! other than `Send` and `Sync` themselves can be declared to imply them using
+//! `#[async_trait(implies(ThreadSafe = Send + Sync))]`.
+//!
+//! The same transformation can be applied to the async methods of an inherent
+//! impl block, or to a free async fn, which is useful for recursion or to give
+//! a function a nameable return type.
+//!
+//! ```
+//! # use async_trait::async_trait;
+//! #
+//! struct Node {
+//!     children: Vec<Node>,
+//! }
+//!
+//! #[async_trait]
+//! impl Node {
+//!     async fn count(&self) -> usize {
+//!         let mut count = 1;
+//!         for child in &self.children {
+//!             count += child.count().await;
+//!         }
+//!         count
+//!     }
+//! }
+//! ```
+//!
 //! <br><br>
 //!
 //! # Non-threadsafe futures
@@ -165,6 +199,425 @@
 //! async trait macro as `#[async_trait(?Send)]` on both the trait and the impl
 //! blocks.
 //!
+//! An impl whose attribute does not match the one on the trait is reported
+//! with an error that says which of the two forms the trait uses.
+//!
+//! If only some of the methods in a trait need to hold non-threadsafe values
+//! across an await point, the same attribute can be written on those
+//! individual methods instead. Like the trait-level attribute, it needs to be
+//! repeated on the corresponding method in every impl.
+//!
+//! ```
+//! # use async_trait::async_trait;
+//! # use std::rc::Rc;
+//! #
+//! #[async_trait]
+//! pub trait Cache {
+//!     // Returns Pin<Box<dyn Future<Output = u8> + Send + 'async_trait>>
+//!     async fn get(&self, key: u8) -> u8;
+//!
+//!     // Returns Pin<Box<dyn Future<Output = ()> + 'async_trait>>
+//!     #[async_trait(?Send)]
+//!     async fn insert(&self, key: u8, value: Rc<u8>);
+//! }
+//! ```
+//!
+//! Where the futures are only non-threadsafe on some targets, the attribute
+//! can take a cfg predicate: `#[async_trait(?Send if cfg(target_arch =
+//! "wasm32"))]` expands to the `?Send` form when the predicate holds and to
+//! the Send form otherwise. Write the same predicate on the trait and on each
+//! impl.
+//!
+//! Code that needs both flavors of the same trait can have them generated from
+//! one definition. Every implementation of the Send variant is also an
+//! implementation of the local one.
+//!
+//! ```
+//! # use async_trait::async_trait;
+//! #
+//! #[async_trait(variants(Send = SendSink, Local = Sink))]
+//! pub trait Sink {
+//!     async fn write(&mut self, bytes: &[u8]);
+//! }
+//!
+//! // Then implement either one.
+//! #[async_trait]
+//! impl SendSink for Vec<u8> {
+//!     async fn write(&mut self, bytes: &[u8]) {
+//!         self.extend_from_slice(bytes);
+//!     }
+//! }
+//!
+//! # async fn test(sink: &mut dyn Sink) {
+//! #     sink.write(b"...").await;
+//! # }
+//! ```
+//!
+//! In the other direction, `#[async_trait(Sync)]` makes the returned futures
+//! `dyn Future + Send + Sync`, for futures that get shared between threads
+//! while pending. Default method bodies then require `Self: Sync`. This too
+//! can be written on the trait and impl blocks or on individual methods.
+//!
+//! <br>
+//!
+//! # Forwarding impls
+//!
+//! Impls of the trait for `Box<T>`, `Arc<T>`, `&T` and `&mut T` that forward
+//! every method to `T` can be generated by listing them in `forward(...)` on
+//! the trait.
+//!
+//! ```
+//! # use async_trait::async_trait;
+//! # use std::sync::Arc;
+//! #
+//! #[async_trait(forward(Box, Arc, Ref, RefMut))]
+//! pub trait Store {
+//!     async fn get(&self, key: u64) -> Option<String>;
+//! }
+//!
+//! fn share(store: Box<dyn Store + Send + Sync>) -> Arc<impl Store> {
+//!     Arc::new(store)
+//! }
+//! ```
+//!
+//! Methods taking `&mut self` can only be forwarded through `Box` and
+//! `RefMut`, and methods taking `self` or `self: Box<Self>` only through
+//! `Box`. If a default method body requires `Self: Sync` or `Self: Send`, the
+//! forwarding impls require the same of `T`.
+//!
+//! <br>
+//!
+//! # Mocking
+//!
+//! Writing `mock` in the attribute on a trait generates a `MockTrait` struct
+//! for use in tests. Every method gets an `expect_*` method that queues up a
+//! closure to answer the next call. Calls without a queued closure panic, and
+//! so does dropping the mock while some closures have not been used.
+//!
+//! ```
+//! # use async_trait::async_trait;
+//! #
+//! #[async_trait(mock)]
+//! pub trait Store {
+//!     async fn get(&self, key: u64) -> Option<String>;
+//! }
+//!
+//! #[cfg(test)]
+//! fn mock_store() -> MockStore {
+//!     let mut store = MockStore::new();
+//!     store.expect_get(|key| Some(key.to_string()));
+//!     store
+//! }
+//! ```
+//!
+//! The mock is only compiled under `cfg(test)`. A different condition can be
+//! given in parentheses, as in `mock(any(test, feature = "mock"))`. Mocks are
+//! supported for traits that contain only methods taking `&self` or `&mut
+//! self`, without generic parameters of their own, and whose return type does
+//! not borrow.
+//!
+//! <br>
+//!
+//! # Custom future types
+//!
+//! The return type of the transformed methods can be replaced by any type
+//! constructor that takes the lifetime and the output type, such as
+//! [`futures::future::BoxFuture`][BoxFuture]. Write the same `future = path` on
+//! the trait and on the impl blocks.
+//!
+//! [BoxFuture]: https://docs.rs/futures/0.3/futures/future/type.BoxFuture.html
+//!
+//! ```
+//! # use async_trait::async_trait;
+//! use futures::future::BoxFuture;
+//!
+//! #[async_trait(future = BoxFuture)]
+//! pub trait Lookup {
+//!     // Returns BoxFuture<'async_trait, Option<String>>
+//!     async fn get(&self, key: u64) -> Option<String>;
+//! }
+//! ```
+//!
+//! The generated body produces `Pin<Box<dyn Future<Output = T> + Send + 'a>>`
+//! (or without `Send` when using `?Send`) and converts it into the custom type
+//! using its `From` impl.
+//!
+//! <br>
+//!
+//! # Static and dynamic dispatch
+//!
+//! Boxing every future costs an allocation even for callers that are generic
+//! over the trait and never need `dyn`. Writing `#[async_trait(dyn = DynName)]`
+//! on a trait leaves its async fns native, returning `impl Future + Send`, and
+//! generates a second trait `DynName` with the usual boxed signatures, which is
+//! implemented for every type that implements the original trait.
+//!
+//! ```
+//! # use async_trait::async_trait;
+//! #
+//! #[async_trait(dyn = DynPlugin)]
+//! pub trait Plugin {
+//!     async fn load(&self) -> bool;
+//! }
+//!
+//! struct Noop;
+//!
+//! // Implemented without the macro, using native async fn.
+//! impl Plugin for Noop {
+//!     async fn load(&self) -> bool {
+//!         true
+//!     }
+//! }
+//!
+//! let registry: Vec<Box<dyn DynPlugin>> = vec![Box::new(Noop)];
+//! ```
+//!
+//! Associated consts are left out of the dyn-compatible trait. This requires
+//! Rust 1.75 or newer.
+//!
+//! <br>
+//!
+//! # Returning impl Trait
+//!
+//! An async fn whose return type is `impl Trait` would make the trait not dyn
+//! compatible, so the returned value is boxed into a `Box<dyn Trait +
+//! 'async_trait>` instead. The body of the method keeps returning the
+//! unboxed value. Async fns of inherent impls and free async fns keep their
+//! `impl Trait` return type as is.
+//!
+//! ```
+//! # use async_trait::async_trait;
+//! #
+//! #[async_trait]
+//! pub trait Source {
+//!     // Returns Pin<Box<dyn Future<
+//!     //     Output = Box<dyn Iterator<Item = u8> + Send + 'async_trait>,
+//!     // > + Send + 'async_trait>>
+//!     async fn items(&self) -> impl Iterator<Item = u8> + Send;
+//! }
+//!
+//! struct Empty;
+//!
+//! #[async_trait]
+//! impl Source for Empty {
+//!     async fn items(&self) -> impl Iterator<Item = u8> + Send {
+//!         std::iter::empty()
+//!     }
+//! }
+//! ```
+//!
+//! <br>
+//!
+//! # Methods returning futures
+//!
+//! A method that is not async but returns `impl Future<Output = T>` is
+//! treated the same as `async fn` returning `T`, and lowered to a boxed
+//! future. This form lets the body do some work before the future is created,
+//! and choose exactly what the future captures. Like for `async fn`, the future
+//! is Send unless the trait or impl, or the method itself, is marked
+//! `#[async_trait(?Send)]`, whether or not the signature spells out `+ Send`.
+//!
+//! ```
+//! # use async_trait::async_trait;
+//! # use std::future::Future;
+//! #
+//! #[async_trait]
+//! pub trait Resolver {
+//!     fn resolve(&self, name: &str) -> impl Future<Output = u32> + Send;
+//! }
+//!
+//! struct Fixed;
+//!
+//! #[async_trait]
+//! impl Resolver for Fixed {
+//!     fn resolve(&self, name: &str) -> impl Future<Output = u32> + Send {
+//!         // The future does not borrow `name`.
+//!         let len = name.len() as u32;
+//!         async move { len }
+//!     }
+//! }
+//! ```
+//!
+//! <br>
+//!
+//! # Static futures
+//!
+//! The future returned by an async method normally borrows from the
+//! arguments, so it cannot be handed to a spawn function that requires
+//! `'static`. Marking a method with `#[async_trait('static)]`, in the trait
+//! and in the impls, makes it return `Pin<Box<dyn Future + Send + 'static>>`
+//! instead. Such a method must take its receiver and arguments by value.
+//!
+//! ```
+//! # use async_trait::async_trait;
+//! # use std::sync::Arc;
+//! #
+//! #[async_trait]
+//! pub trait Worker {
+//!     #[async_trait('static)]
+//!     async fn work(self: Arc<Self>, input: Vec<u8>) -> usize;
+//! }
+//! ```
+//!
+//! <br>
+//!
+//! # Caller location
+//!
+//! An async method marked `#[track_caller]` captures the location of its
+//! caller when it is called, before the future is first polled. Within the
+//! method body, `Location::caller()` returns that location, so assertion
+//! helpers can report the call site of the method. Panics raised by the body
+//! itself still point at the line in the body, and closures or async blocks
+//! nested in the body see their own location as usual.
+//!
+//! ```
+//! # use async_trait::async_trait;
+//! # use std::panic::Location;
+//! #
+//! #[async_trait]
+//! pub trait Check {
+//!     #[track_caller]
+//!     async fn check(&self) {
+//!         let caller = Location::caller();
+//!         if !self.ok().await {
+//!             panic!("check failed at {}", caller);
+//!         }
+//!     }
+//!
+//!     async fn ok(&self) -> bool;
+//! }
+//! ```
+//!
+//! <br>
+//!
+//! # Attribute placement
+//!
+//! Attributes written on an async method stay on the generated method, whose
+//! body only boxes the future. Attributes that need to see the original body,
+//! such as lint levels, can be moved onto the async block instead by wrapping
+//! them in `#[async_trait::inner(...)]`. For symmetry,
+//! `#[async_trait::outer(...)]` explicitly keeps attributes on the method. An
+//! async method declared without a body has no async block, so
+//! `#[async_trait::inner(...)]` on it is an error.
+//!
+//! ```
+//! # use async_trait::async_trait;
+//! #
+//! #[async_trait]
+//! pub trait Job {
+//!     #[async_trait::outer(must_use)]
+//!     #[async_trait::inner(allow(unused_variables))]
+//!     async fn run(&self, retries: u8) -> bool {
+//!         false
+//!     }
+//! }
+//! ```
+//!
+//! <br>
+//!
+//! # Tracing
+//!
+//! With `#[async_trait(instrument)]` on an impl block, or on a single async
+//! method, the future of each async method runs inside a [tracing] span named
+//! after the trait and method, such as `"Service::call"`. Arguments other than
+//! self are recorded as fields using their Debug representation. Methods
+//! returning `impl Future` are instrumented the same way, with the span created
+//! when the method is called. The level of the span and the arguments to leave
+//! out can be chosen with
+//! `#[async_trait(instrument(level = "debug", skip(password)))]`. The expanded
+//! code refers to the `tracing` crate, which needs to be a dependency of the
+//! crate using this.
+//!
+//! [tracing]: https://docs.rs/tracing
+//!
+//! ```
+//! # use async_trait::async_trait;
+//! #
+//! #[async_trait]
+//! pub trait Service {
+//!     async fn call(&self, request: u8, password: String) -> u8;
+//! }
+//!
+//! pub struct Echo;
+//!
+//! #[async_trait(instrument(skip(password)))]
+//! impl Service for Echo {
+//!     async fn call(&self, request: u8, password: String) -> u8 {
+//!         request
+//!     }
+//! }
+//! ```
+//!
+//! <br>
+//!
+//! # Wrapping futures
+//!
+//! For behavior that applies to every method, such as metrics or deadlines,
+//! `#[async_trait(wrap = path::to::hook)]` passes the future of each method
+//! through a function of your own before it gets boxed, along with the name of
+//! the method in the form `"Trait::method"`. The hook is called as
+//! `hook(name, future)` and returns the future that the method produces, which
+//! needs to be Send unless the method is `?Send`.
+//!
+//! ```
+//! # use async_trait::async_trait;
+//! # use std::future::Future;
+//! #
+//! pub async fn observe<F: Future>(name: &'static str, future: F) -> F::Output {
+//!     let output = future.await;
+//!     println!("{} completed", name);
+//!     output
+//! }
+//!
+//! #[async_trait]
+//! pub trait Service {
+//!     async fn call(&self, request: u8) -> u8;
+//! }
+//!
+//! pub struct Echo;
+//!
+//! #[async_trait(wrap = observe)]
+//! impl Service for Echo {
+//!     async fn call(&self, request: u8) -> u8 {
+//!         request
+//!     }
+//! }
+//! ```
+//!
+//! <br>
+//!
+//! # No-std
+//!
+//! The expanded code refers to the `Box` type by the name `Box`, so a
+//! `#![no_std]` crate that has access to the `alloc` crate can bring
+//! `alloc::boxed::Box` into scope. Alternatively the macro can be pointed at a
+//! path that provides `boxed::Box`, on both the trait and the impl blocks,
+//! which also keeps the expansion independent of whatever `Box` is in scope.
+//!
+//! ```
+//! # const IGNORE: &str = stringify! {
+//! #![no_std]
+//!
+//! extern crate alloc;
+//! # };
+//! # extern crate alloc;
+//!
+//! use async_trait::async_trait;
+//!
+//! #[async_trait(alloc = ::alloc)]
+//! pub trait Device {
+//!     async fn reset(&mut self);
+//! }
+//! ```
+//!
+//! Any path to a module that contains `boxed::Box`, such as a re-export of the
+//! alloc crate from some other crate, works too.
+//!
+//! Unlike `Box`, `Arc` is not in the prelude, so the impl generated by
+//! `forward(Arc)` refers to `::std::sync::Arc` by default. A `#![no_std]` crate
+//! that forwards through `Arc` needs `alloc = path`, which makes it refer to
+//! `path::sync::Arc` instead.
+//!
 //! <br>
 //!
 //! # Elided lifetimes
@@ -236,9 +689,15 @@
 extern crate proc_macro;
 
 mod args;
+mod attrs;
 mod bound;
+mod caller;
+mod check;
 mod expand;
+mod forward;
+mod instrument;
 mod lifetime;
+mod mock;
 mod parse;
 mod receiver;
 mod verbatim;
@@ -247,13 +706,14 @@ use crate::args::Args;
 use crate::expand::expand;
 use crate::parse::Item;
 use proc_macro::TokenStream;
-use quote::quote;
 use syn::parse_macro_input;
 
 #[proc_macro_attribute]
 pub fn async_trait(args: TokenStream, input: TokenStream) -> TokenStream {
     let args = parse_macro_input!(args as Args);
     let mut item = parse_macro_input!(input as Item);
-    expand(&mut item, args.local);
-    TokenStream::from(quote!(#item))
+    match expand(&mut item, &args) {
+        Ok(expanded) => TokenStream::from(expanded),
+        Err(err) => TokenStream::from(err.to_compile_error()),
+    }
 }
@@ -2,8 +2,8 @@ use proc_macro2::{Span, TokenStream};
 use std::mem;
 use syn::visit_mut::{self, VisitMut};
 use syn::{
-    parse_quote_spanned, token, Expr, GenericArgument, Lifetime, ReceiverKind, ReturnType, Token,
-    Type, TypeFnPtr, TypeImplTrait, TypeParen, TypePtr, TypeReference,
+    token, Expr, GenericArgument, Lifetime, ReceiverKind, ReturnType, Token, Type, TypeFnPtr,
+    TypeImplTrait, TypeParamBound, TypeParen, TypePtr, TypeReference,
 };
 
 pub struct CollectLifetimes {
@@ -69,13 +69,14 @@ impl VisitMut for CollectLifetimes {
     }
 }
 
-pub struct AddLifetimeToImplTrait;
+pub struct AddLifetimeToImplTrait(pub Lifetime);
 
 impl VisitMut for AddLifetimeToImplTrait {
     fn visit_type_impl_trait_mut(&mut self, ty: &mut TypeImplTrait) {
         let span = ty.impl_token.span;
-        let lifetime = parse_quote_spanned!(span=> 'async_trait);
-        ty.bounds.insert(0, lifetime);
+        let mut lifetime = self.0.clone();
+        lifetime.set_span(span);
+        ty.bounds.insert(0, TypeParamBound::Lifetime(lifetime));
         if let Some(punct) = ty.bounds.pairs_mut().next().unwrap().punct_mut() {
             punct.span = span;
         }
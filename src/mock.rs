@@ -0,0 +1,284 @@
+use crate::args::{is_async_trait_attr, Args};
+use crate::expand::{asyncify, expand_impl};
+use crate::forward::forward_args;
+use crate::lifetime::CollectLifetimes;
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::parse::{Error, Result};
+use syn::visit_mut::{self, VisitMut};
+use syn::{
+    parse_quote, FnArg, ItemImpl, ItemTrait, ReceiverKind, ReturnType, TraitItem, Type,
+    TypeImplTrait, TypePath,
+};
+
+// Input:
+//     #[async_trait(mock)]
+//     pub trait Trait {
+//         async fn f(&self, x: u8) -> Ret;
+//     }
+//
+// Output:
+//     #[cfg(test)]
+//     pub struct MockTrait {
+//         f: Mutex<VecDeque<Box<dyn FnOnce(u8) -> Ret + Send>>>,
+//     }
+//
+//     #[cfg(test)]
+//     impl MockTrait {
+//         pub fn new() -> Self;
+//         pub fn expect_f(&mut self, f: impl FnOnce(u8) -> Ret + Send + 'static) -> &mut Self;
+//     }
+//
+//     #[cfg(test)]
+//     #[async_trait]
+//     impl Trait for MockTrait {
+//         async fn f(&self, x: u8) -> Ret {
+//             (self.f.lock().unwrap().pop_front().expect(...))(x)
+//         }
+//     }
+//
+//     #[cfg(test)]
+//     impl Drop for MockTrait {
+//         // Panics if any expectation was left unused.
+//     }
+pub fn mock(input: &ItemTrait, args: &Args) -> Result<Option<TokenStream>> {
+    let predicate = match &args.mock {
+        Some(predicate) => predicate,
+        None => return Ok(None),
+    };
+
+    let vis = &input.vis;
+    let ident = &input.ident;
+    let mock_ident = format_ident!("Mock{}", ident);
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let type_params = input.generics.type_params().map(|param| &param.ident);
+    let lifetimes = input.generics.lifetimes().map(|param| &param.lifetime);
+
+    let mut fields = Vec::new();
+    let mut field_inits = Vec::new();
+    let mut expectations = Vec::new();
+    let mut methods = Vec::new();
+    let mut unused = Vec::new();
+    for item in &input.items {
+        let method = match item {
+            TraitItem::Fn(method) => method,
+            _ => {
+                let msg = "mock only supports traits that contain nothing but methods";
+                return Err(Error::new_spanned(item, msg));
+            }
+        };
+
+        // A method returning impl Future is mocked as an async fn.
+        let mut sig = method.sig.clone();
+        let future_return = asyncify(&mut sig);
+        let sig = &sig;
+        if !sig.generics.params.is_empty() {
+            let msg = "mock does not support generic methods";
+            return Err(Error::new_spanned(&sig.generics, msg));
+        }
+        let by_reference = match sig.receiver() {
+            Some(receiver) => match receiver.kind {
+                ReceiverKind::Reference(..) => true,
+                _ => false,
+            },
+            None => false,
+        };
+        if !by_reference {
+            let msg = "mock only supports methods taking &self or &mut self";
+            return Err(Error::new_spanned(sig, msg));
+        }
+
+        let mut arg_types = Vec::new();
+        for arg in &sig.inputs {
+            if let FnArg::Typed(arg) = arg {
+                if unsupported(&arg.ty) {
+                    let msg = "mock does not support arguments involving Self or impl Trait";
+                    return Err(Error::new_spanned(&arg.ty, msg));
+                }
+                arg_types.push(&arg.ty);
+            }
+        }
+        let ret = match &sig.output {
+            ReturnType::Default => quote!(()),
+            ReturnType::Type(_, ret) => {
+                let mut lifetimes = CollectLifetimes::new();
+                lifetimes.visit_type_mut(&mut ret.clone());
+                if unsupported(ret)
+                    || !lifetimes.elided.is_empty()
+                    || lifetimes
+                        .explicit
+                        .iter()
+                        .any(|lifetime| lifetime.ident != "static")
+                {
+                    let msg = "mock does not support return types that borrow or involve Self or impl Trait";
+                    return Err(Error::new_spanned(ret, msg));
+                }
+                quote!(#ret)
+            }
+        };
+
+        let attrs: Vec<_> = method
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("cfg"))
+            .collect();
+        let method_ident = &sig.ident;
+        let expect_ident = format_ident!("expect_{}", method_ident);
+        let closure =
+            quote!(dyn ::core::ops::FnOnce(#(#arg_types),*) -> #ret + ::core::marker::Send);
+        fields.push(quote! {
+            #(#attrs)*
+            #method_ident: ::std::sync::Mutex<::std::collections::VecDeque<::std::boxed::Box<#closure>>>,
+        });
+        field_inits.push(quote! {
+            #(#attrs)*
+            #method_ident: ::core::default::Default::default(),
+        });
+        let msg = format!(
+            "{}::{}: {{}} expected call(s) were not made",
+            mock_ident, method_ident,
+        );
+        unused.push(quote! {
+            #(#attrs)*
+            {
+                let remaining = self.#method_ident.get_mut().unwrap().len();
+                ::std::assert!(remaining == 0, #msg, remaining);
+            }
+        });
+        let doc = format!(
+            "Expect one more call to `{}`, which will be answered by the given closure.",
+            method_ident,
+        );
+        expectations.push(quote! {
+            #(#attrs)*
+            #[doc = #doc]
+            #vis fn #expect_ident(
+                &mut self,
+                f: impl ::core::ops::FnOnce(#(#arg_types),*) -> #ret + ::core::marker::Send + 'static,
+            ) -> &mut Self {
+                self.#method_ident
+                    .get_mut()
+                    .unwrap()
+                    .push_back(::std::boxed::Box::new(f));
+                self
+            }
+        });
+
+        let mut sig = sig.clone();
+        let call_args = forward_args(&mut sig);
+        let call_args = &call_args[1..];
+        let method_attrs = method
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("cfg") || is_async_trait_attr(attr));
+        let future_args = match &future_return {
+            Some(future) if future.sync => Some(quote!(#[async_trait(Sync)])),
+            _ => None,
+        };
+        let msg = format!("unexpected call to {}::{}", mock_ident, method_ident);
+        methods.push(quote! {
+            #(#method_attrs)*
+            #future_args
+            #sig {
+                let f = self.#method_ident.lock().unwrap().pop_front().expect(#msg);
+                f(#(#call_args),*)
+            }
+        });
+    }
+
+    let mut trait_impl: ItemImpl = parse_quote! {
+        impl #impl_generics #ident #ty_generics for #mock_ident #ty_generics #where_clause {
+            #(#methods)*
+        }
+    };
+    trait_impl.unsafety = input.unsafety;
+    // Arguments are moved into the returned future and then into a closure
+    // that may have been registered on another thread.
+    let send = if args.local {
+        None
+    } else {
+        Some(quote!(::core::marker::Send+))
+    };
+    let sync = if args.sync {
+        Some(quote!(::core::marker::Sync+))
+    } else {
+        None
+    };
+    for param in input.generics.type_params() {
+        let param = &param.ident;
+        trait_impl
+            .generics
+            .make_where_clause()
+            .predicates
+            .push(parse_quote!(#param: #send #sync 'static));
+    }
+    expand_impl(&mut trait_impl, args)?;
+
+    let doc = format!(
+        "Mock implementation of [`{}`] that answers each call using the closures registered by the `expect_*` methods, in order.",
+        ident,
+    );
+    Ok(Some(quote! {
+        #[cfg(#predicate)]
+        #[doc = #doc]
+        #vis struct #mock_ident #impl_generics #where_clause {
+            #(#fields)*
+            __marker: ::core::marker::PhantomData<fn() -> (#(#type_params,)* #(&#lifetimes (),)*)>,
+        }
+
+        #[cfg(#predicate)]
+        impl #impl_generics #mock_ident #ty_generics #where_clause {
+            /// Creates a mock with no expected calls.
+            #vis fn new() -> Self {
+                #mock_ident {
+                    #(#field_inits)*
+                    __marker: ::core::marker::PhantomData,
+                }
+            }
+
+            #(#expectations)*
+        }
+
+        #[cfg(#predicate)]
+        impl #impl_generics ::core::default::Default for #mock_ident #ty_generics #where_clause {
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+
+        #[cfg(#predicate)]
+        #trait_impl
+
+        #[cfg(#predicate)]
+        impl #impl_generics ::core::ops::Drop for #mock_ident #ty_generics #where_clause {
+            fn drop(&mut self) {
+                if !::std::thread::panicking() {
+                    #(#unused)*
+                }
+            }
+        }
+    }))
+}
+
+// Whether the type mentions Self or impl Trait, neither of which can appear in
+// the closures stored by the mock.
+fn unsupported(ty: &Type) -> bool {
+    struct Unsupported(bool);
+
+    impl VisitMut for Unsupported {
+        fn visit_type_path_mut(&mut self, ty: &mut TypePath) {
+            if ty.qself.is_none() && ty.path.segments[0].ident == "Self" {
+                self.0 = true;
+            }
+            visit_mut::visit_type_path_mut(self, ty);
+        }
+
+        fn visit_type_impl_trait_mut(&mut self, _ty: &mut TypeImplTrait) {
+            self.0 = true;
+        }
+    }
+
+    let mut visitor = Unsupported(false);
+    visitor.visit_type_mut(&mut ty.clone());
+    visitor.0
+}
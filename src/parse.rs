@@ -1,32 +1,38 @@
-use proc_macro2::Span;
-use syn::parse::{Error, Parse, ParseStream, Result};
-use syn::{Attribute, ItemImpl, ItemTrait, Token};
+use syn::parse::{Parse, ParseStream, Result};
+use syn::{Attribute, ItemFn, ItemImpl, ItemTrait, Token, Visibility};
 
+#[derive(Clone)]
 pub enum Item {
     Trait(ItemTrait),
     Impl(ItemImpl),
+    Fn(ItemFn),
 }
 
 impl Parse for Item {
     fn parse(input: ParseStream) -> Result<Self> {
         let attrs = input.call(Attribute::parse_outer)?;
-        let mut lookahead = input.lookahead1();
+        let ahead = input.fork();
+        ahead.parse::<Visibility>()?;
+        let mut lookahead = ahead.lookahead1();
         if lookahead.peek(Token![unsafe]) {
-            let ahead = input.fork();
             ahead.parse::<Token![unsafe]>()?;
             lookahead = ahead.lookahead1();
         }
-        if lookahead.peek(Token![pub]) || lookahead.peek(Token![trait]) {
+        if lookahead.peek(Token![trait]) {
             let mut item: ItemTrait = input.parse()?;
             item.attrs = attrs;
             Ok(Item::Trait(item))
         } else if lookahead.peek(Token![impl]) {
             let mut item: ItemImpl = input.parse()?;
-            if item.trait_.is_none() {
-                return Err(Error::new(Span::call_site(), "expected a trait impl"));
-            }
             item.attrs = attrs;
             Ok(Item::Impl(item))
+        } else if lookahead.peek(Token![async])
+            || lookahead.peek(Token![fn])
+            || lookahead.peek(Token![extern])
+        {
+            let mut item: ItemFn = input.parse()?;
+            item.attrs = attrs;
+            Ok(Item::Fn(item))
         } else {
             Err(lookahead.error())
         }
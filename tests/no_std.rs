@@ -0,0 +1,39 @@
+#![no_std]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use async_trait::async_trait;
+
+#[async_trait]
+pub trait Trait {
+    async fn f(&self) -> u8 {
+        0
+    }
+
+    async fn g(&self);
+}
+
+pub struct Struct;
+
+#[async_trait]
+impl Trait for Struct {
+    async fn g(&self) {}
+}
+
+#[async_trait(?Send)]
+pub trait LocalTrait {
+    async fn f(&self) -> Box<u8>;
+}
+
+#[async_trait(?Send)]
+impl LocalTrait for Struct {
+    async fn f(&self) -> Box<u8> {
+        Box::new(0)
+    }
+}
+
+#[async_trait(forward(Box, Arc), alloc = ::alloc)]
+pub trait Shared {
+    async fn f(&self);
+}
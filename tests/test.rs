@@ -1,6 +1,11 @@
 #![cfg_attr(
     async_trait_nightly_testing,
-    feature(impl_trait_in_assoc_type, min_specialization, never_type)
+    feature(
+        arbitrary_self_types,
+        impl_trait_in_assoc_type,
+        min_specialization,
+        never_type
+    )
 )]
 #![deny(rust_2021_compatibility, unused_qualifications)]
 #![allow(
@@ -19,6 +24,8 @@
     clippy::unused_async
 )]
 
+extern crate alloc;
+
 use async_trait::async_trait;
 
 pub mod executor;
@@ -36,224 +43,1437 @@ trait Trait {
     {
     }
 
-    async fn selfref(&self) {}
+    async fn selfref(&self) {}
+
+    async fn selfmut(&mut self) {}
+
+    async fn required() -> Self::Assoc;
+
+    async fn elided_lifetime(_x: &str) {}
+
+    async fn explicit_lifetime<'a>(_x: &'a str) {}
+
+    async fn generic_type_param<T: Send>(x: Box<T>) -> T {
+        *x
+    }
+
+    async fn calls(&self) {
+        self.selfref().await;
+        Self::elided_lifetime("").await;
+        <Self>::elided_lifetime("").await;
+    }
+
+    async fn calls_mut(&mut self) {
+        self.selfmut().await;
+    }
+}
+
+struct Struct;
+
+#[async_trait]
+impl Trait for Struct {
+    type Assoc = ();
+
+    async fn selfvalue(self) {}
+
+    async fn selfref(&self) {}
+
+    async fn selfmut(&mut self) {}
+
+    async fn required() -> Self::Assoc {}
+
+    async fn elided_lifetime(_x: &str) {}
+
+    async fn explicit_lifetime<'a>(_x: &'a str) {}
+
+    async fn generic_type_param<T: Send>(x: Box<T>) -> T {
+        *x
+    }
+
+    async fn calls(&self) {
+        self.selfref().await;
+        Self::elided_lifetime("").await;
+        <Self>::elided_lifetime("").await;
+    }
+
+    async fn calls_mut(&mut self) {
+        self.selfmut().await;
+    }
+}
+
+pub async fn test() {
+    let mut s = Struct;
+    s.selfref().await;
+    s.selfmut().await;
+    s.selfvalue().await;
+
+    Struct::required().await;
+    Struct::elided_lifetime("").await;
+    Struct::explicit_lifetime("").await;
+    Struct::generic_type_param(Box::new("")).await;
+
+    let mut s = Struct;
+    s.calls().await;
+    s.calls_mut().await;
+}
+
+pub async fn test_dyn_compatible_without_default() {
+    #[async_trait]
+    trait DynCompatible {
+        async fn f(&self);
+    }
+
+    #[async_trait]
+    impl DynCompatible for Struct {
+        async fn f(&self) {}
+    }
+
+    let object = &Struct as &dyn DynCompatible;
+    object.f().await;
+}
+
+pub async fn test_dyn_compatible_with_default() {
+    #[async_trait]
+    trait DynCompatible: Sync {
+        async fn f(&self) {}
+    }
+
+    #[async_trait]
+    impl DynCompatible for Struct {
+        async fn f(&self) {}
+    }
+
+    let object = &Struct as &dyn DynCompatible;
+    object.f().await;
+}
+
+pub async fn test_dyn_compatible_no_send() {
+    #[async_trait(?Send)]
+    trait DynCompatible: Sync {
+        async fn f(&self) {}
+    }
+
+    #[async_trait(?Send)]
+    impl DynCompatible for Struct {
+        async fn f(&self) {}
+    }
+
+    let object = &Struct as &dyn DynCompatible;
+    object.f().await;
+}
+
+pub async fn test_method_no_send() {
+    use std::rc::Rc;
+
+    #[async_trait]
+    trait MethodNoSend {
+        async fn send(&self);
+
+        #[async_trait(?Send)]
+        async fn local(&self, rc: Rc<u8>) {
+            async {}.await;
+            let _ = rc;
+        }
+    }
+
+    #[async_trait]
+    impl MethodNoSend for Struct {
+        async fn send(&self) {}
+
+        #[async_trait(?Send)]
+        async fn local(&self, rc: Rc<u8>) {
+            async {}.await;
+            let _ = rc;
+        }
+    }
+
+    fn assert_send<T: Send>(_: T) {}
+
+    let object = &Struct as &dyn MethodNoSend;
+    assert_send(object.send());
+    object.send().await;
+    object.local(Rc::new(0)).await;
+}
+
+#[async_trait]
+pub unsafe trait UnsafeTrait {}
+
+#[async_trait]
+unsafe impl UnsafeTrait for () {}
+
+#[async_trait]
+#[allow(dead_code)]
+pub(crate) unsafe trait UnsafeTraitPubCrate {}
+
+#[async_trait]
+#[allow(dead_code)]
+unsafe trait UnsafeTraitPrivate {}
+
+pub async fn test_can_destruct() {
+    #[async_trait]
+    trait CanDestruct {
+        async fn f(&self, foos: (u8, u8, u8, u8));
+    }
+
+    #[async_trait]
+    impl CanDestruct for Struct {
+        async fn f(&self, (a, ref mut b, ref c, d): (u8, u8, u8, u8)) {
+            let _a: u8 = a;
+            let _b: &mut u8 = b;
+            let _c: &u8 = c;
+            let _d: u8 = d;
+        }
+    }
+
+    let _ = <Struct as CanDestruct>::f;
+}
+
+pub async fn test_self_in_macro() {
+    #[async_trait]
+    trait Trait {
+        async fn a(self);
+        async fn b(&mut self);
+        async fn c(&self);
+    }
+
+    #[async_trait]
+    impl Trait for String {
+        async fn a(self) {
+            println!("{}", self);
+        }
+        async fn b(&mut self) {
+            println!("{}", self);
+        }
+        async fn c(&self) {
+            println!("{}", self);
+        }
+    }
+
+    let _ = <String as Trait>::a;
+    let _ = <String as Trait>::b;
+    let _ = <String as Trait>::c;
+}
+
+pub async fn test_inference() {
+    #[async_trait]
+    pub trait Trait {
+        async fn f() -> Box<dyn Iterator<Item = ()>> {
+            Box::new(std::iter::empty())
+        }
+    }
+
+    impl Trait for () {}
+
+    let _ = <() as Trait>::f;
+}
+
+pub async fn test_internal_items() {
+    #[async_trait]
+    #[allow(dead_code, clippy::items_after_statements)]
+    pub trait Trait: Sized {
+        async fn f(self) {
+            struct Struct;
+
+            impl Struct {
+                fn f(self) {
+                    let _ = self;
+                }
+            }
+        }
+    }
+}
+
+pub async fn test_unimplemented() {
+    #[async_trait]
+    pub trait Trait {
+        async fn f() {
+            unimplemented!()
+        }
+    }
+
+    impl Trait for () {}
+
+    let _ = <() as Trait>::f;
+}
+
+pub mod no_implicit_prelude {
+    #![no_implicit_prelude]
+
+    use ::async_trait::async_trait;
+
+    // Must not be picked up by the expansion when the path to Box is given.
+    pub struct Box;
+
+    #[async_trait(alloc = ::std)]
+    pub trait Trait {
+        async fn f(&self) -> u8 {
+            0
+        }
+    }
+
+    pub struct Struct;
+
+    #[async_trait(alloc = ::std)]
+    impl Trait for Struct {
+        async fn f(&self) -> u8 {
+            1
+        }
+    }
+}
+
+pub mod alloc_path {
+    use async_trait::async_trait;
+
+    #[async_trait(alloc = ::alloc)]
+    pub trait Trait {
+        async fn f(&self);
+    }
+
+    pub struct Struct;
+
+    #[async_trait(alloc = ::alloc)]
+    impl Trait for Struct {
+        async fn f(&self) {}
+    }
+
+    #[async_trait(?Send, alloc = ::alloc)]
+    pub trait LocalTrait {
+        async fn f(&self) {}
+    }
+}
+
+pub mod forward {
+    use crate::executor;
+    use async_trait::async_trait;
+    use std::sync::Arc;
+
+    #[async_trait(forward(Box, Arc, Ref, RefMut))]
+    pub trait Shared<T> {
+        type Item;
+
+        fn len(&self) -> usize;
+
+        fn is_empty(&self) -> bool;
+
+        async fn get(&self, index: usize) -> Option<&T>;
+
+        async fn describe(&self) -> String {
+            format!("{} items", self.len())
+        }
+    }
+
+    #[async_trait(forward(Box, RefMut))]
+    pub trait Unique {
+        const CAPACITY: usize;
+
+        async fn allocate() -> Vec<u8>;
+
+        async fn push(&mut self, value: u8);
+    }
+
+    #[async_trait(forward(Box))]
+    pub trait Owned {
+        async fn into_vec(self) -> Vec<u8>;
+
+        async fn boxed(self: Box<Self>) -> usize;
+    }
+
+    pub struct Buffer(Vec<u8>);
+
+    #[async_trait]
+    impl Shared<u8> for Buffer {
+        type Item = u8;
+
+        fn len(&self) -> usize {
+            self.0.len()
+        }
+
+        fn is_empty(&self) -> bool {
+            self.0.is_empty()
+        }
+
+        async fn get(&self, index: usize) -> Option<&u8> {
+            self.0.get(index)
+        }
+    }
+
+    #[async_trait]
+    impl Unique for Buffer {
+        const CAPACITY: usize = 8;
+
+        async fn allocate() -> Vec<u8> {
+            Vec::with_capacity(Self::CAPACITY)
+        }
+
+        async fn push(&mut self, value: u8) {
+            self.0.push(value);
+        }
+    }
+
+    #[async_trait]
+    impl Owned for Buffer {
+        async fn into_vec(self) -> Vec<u8> {
+            self.0
+        }
+
+        async fn boxed(self: Box<Self>) -> usize {
+            self.0.len()
+        }
+    }
+
+    #[test]
+    fn test() {
+        let mut buffer = Buffer(executor::block_on_simple(<&mut Buffer>::allocate()));
+        executor::block_on_simple(Unique::push(&mut &mut buffer, 1));
+        let mut boxed = Box::new(buffer);
+        executor::block_on_simple(boxed.push(2));
+        assert_eq!(<Box<Buffer>>::CAPACITY, 8);
+        assert_eq!(executor::block_on_simple(Box::new(boxed).boxed()), 2);
+        let boxed = Box::new(Buffer(vec![1, 2]));
+        assert_eq!(executor::block_on_simple(boxed.into_vec()), [1, 2]);
+
+        let buffer = Buffer(vec![1, 2, 3]);
+        assert_eq!(
+            executor::block_on_simple(Shared::describe(&&buffer)),
+            "3 items"
+        );
+        let shared: Arc<dyn Shared<u8, Item = u8> + Send + Sync> = Arc::new(buffer);
+        assert_eq!(executor::block_on_simple(shared.get(1)), Some(&2));
+        let boxed: Box<dyn Shared<u8, Item = u8> + Send + Sync> = Box::new(shared);
+        assert_eq!(executor::block_on_simple(boxed.describe()), "3 items");
+    }
+}
+
+pub mod impl_trait_return {
+    use crate::executor;
+    use async_trait::async_trait;
+    use std::fmt::Display;
+
+    #[async_trait]
+    pub trait Source {
+        async fn items(&self) -> impl Iterator<Item = u8> + Send;
+
+        async fn label(&self, verbose: bool) -> impl Display {
+            if verbose {
+                return format!("{} items", self.items().await.count());
+            }
+            "items".to_owned()
+        }
+    }
+
+    pub struct Range(u8);
+
+    #[async_trait]
+    impl Source for Range {
+        async fn items(&self) -> impl Iterator<Item = u8> + Send {
+            if self.0 == 0 {
+                return Vec::new().into_iter();
+            }
+            (0..self.0).collect::<Vec<_>>().into_iter()
+        }
+    }
+
+    #[test]
+    fn test() {
+        let source: &dyn Source = &Range(3);
+        let items = executor::block_on_simple(source.items());
+        assert_eq!(items.collect::<Vec<_>>(), [0, 1, 2]);
+        let label = executor::block_on_simple(Range(0).label(true));
+        assert_eq!(label.to_string(), "0 items");
+    }
+}
+
+pub mod future_return {
+    use crate::executor;
+    use async_trait::async_trait;
+    use std::future::Future;
+    use std::rc::Rc;
+
+    #[async_trait]
+    pub trait Counter {
+        fn add(&self, n: u8) -> impl Future<Output = u8> + Send;
+
+        fn double(&self, n: u8) -> impl Future<Output = u8>;
+
+        #[async_trait(?Send)]
+        fn local(&self) -> impl Future<Output = u8> {
+            let n = Rc::new(1);
+            async move { *n }
+        }
+    }
+
+    pub struct Fixed(u8);
+
+    #[async_trait]
+    #[deny(unused_braces)]
+    impl Counter for Fixed {
+        fn add(&self, mut n: u8) -> impl Future<Output = u8> + Send {
+            // Runs eagerly, before the future is polled.
+            n += self.0;
+            async move { n }
+        }
+
+        // Send like the rest of the impl, without spelling out + Send.
+        fn double(&self, n: u8) -> impl Future<Output = u8> {
+            async move { n * 2 }
+        }
+    }
+
+    #[async_trait]
+    impl Fixed {
+        // Left as it is, so the future need not be Send.
+        pub fn rc(&self) -> impl Future<Output = u8> {
+            let n = Rc::new(self.0);
+            async move { *n }
+        }
+    }
+
+    fn assert_send<T: Send>(_: T) {}
+
+    #[test]
+    fn test() {
+        let counter: &dyn Counter = &Fixed(1);
+        assert_send(counter.add(1));
+        assert_eq!(executor::block_on_simple(counter.add(1)), 2);
+        assert_send(counter.double(1));
+        assert_eq!(executor::block_on_simple(counter.double(1)), 2);
+        assert_eq!(executor::block_on_simple(counter.local()), 1);
+        assert_eq!(executor::block_on_simple(Fixed(1).rc()), 1);
+    }
+}
+
+pub mod hand_written_future {
+    use crate::executor;
+    use async_trait::async_trait;
+    use std::future::Future;
+    use std::pin::Pin;
+
+    type BoxFuture = Pin<Box<dyn Future<Output = u8> + Send>>;
+
+    #[async_trait]
+    pub trait Trait {
+        async fn f(&self) -> u8;
+    }
+
+    pub struct Struct;
+
+    // The same signature as the macro generates for an async fn, spelled
+    // with an alias that takes no lifetime.
+    #[async_trait]
+    impl Trait for Struct {
+        fn f<'life0, 'async_trait>(&'life0 self) -> BoxFuture
+        where
+            'life0: 'async_trait,
+            Self: 'async_trait,
+        {
+            Box::pin(async { 1 })
+        }
+    }
+
+    #[test]
+    fn test() {
+        assert_eq!(executor::block_on_simple(Struct.f()), 1);
+    }
+}
+
+pub mod static_future {
+    use crate::executor;
+    use async_trait::async_trait;
+    use std::sync::Arc;
+
+    #[async_trait]
+    pub trait Job {
+        #[async_trait('static)]
+        async fn run(self: Arc<Self>, n: u8) -> u8;
+
+        #[async_trait('static)]
+        async fn run_twice(self: Arc<Self>, n: u8) -> u8 {
+            let n = self.clone().run(n).await;
+            self.run(n).await
+        }
+
+        async fn name(&self) -> &str;
+    }
+
+    pub struct Add(u8);
+
+    #[async_trait]
+    impl Job for Add {
+        #[async_trait('static)]
+        async fn run(self: Arc<Self>, n: u8) -> u8 {
+            n + self.0
+        }
+
+        async fn name(&self) -> &str {
+            "add"
+        }
+    }
+
+    fn assert_spawnable<T: Send + 'static>(future: T) -> T {
+        future
+    }
+
+    #[test]
+    fn test() {
+        let job: Arc<dyn Job + Send + Sync> = Arc::new(Add(1));
+        let future = assert_spawnable(job.clone().run_twice(1));
+        assert_eq!(executor::block_on_simple(future), 3);
+        assert_eq!(executor::block_on_simple(job.name()), "add");
+    }
+}
+
+pub mod sync_future {
+    use crate::executor;
+    use async_trait::async_trait;
+
+    #[async_trait(Sync)]
+    pub trait Lookup {
+        async fn get(&self, key: u8) -> Option<u8>;
+
+        async fn get_or_zero(&self, key: u8) -> u8 {
+            self.get(key).await.unwrap_or(0)
+        }
+    }
+
+    #[async_trait]
+    pub trait Counter {
+        #[async_trait(Sync)]
+        async fn next(&mut self) -> u8;
+
+        async fn peek(&self) -> u8;
+    }
+
+    pub struct Table(Vec<u8>);
+
+    #[async_trait(Sync)]
+    impl Lookup for Table {
+        async fn get(&self, key: u8) -> Option<u8> {
+            self.0.get(key as usize).copied()
+        }
+    }
+
+    #[async_trait]
+    impl Counter for Table {
+        #[async_trait(Sync)]
+        async fn next(&mut self) -> u8 {
+            self.0.push(self.0.len() as u8);
+            self.0.len() as u8
+        }
+
+        async fn peek(&self) -> u8 {
+            self.0.len() as u8
+        }
+    }
+
+    fn assert_sync<T: Send + Sync>(future: T) -> T {
+        future
+    }
+
+    #[test]
+    fn test() {
+        let mut table = Table(vec![7]);
+        let lookup: &(dyn Lookup + Sync) = &table;
+        let future = assert_sync(lookup.get_or_zero(0));
+        assert_eq!(executor::block_on_simple(future), 7);
+        let future = assert_sync(table.next());
+        assert_eq!(executor::block_on_simple(future), 2);
+        assert_eq!(executor::block_on_simple(table.peek()), 2);
+    }
+}
+
+pub mod local_if {
+    use crate::executor;
+    use async_trait::async_trait;
+    use std::rc::Rc;
+
+    // Always local.
+    #[async_trait(?Send if cfg(all()))]
+    pub trait Local {
+        async fn get(&self) -> u8;
+    }
+
+    // Never local.
+    #[async_trait(?Send if cfg(any()))]
+    pub trait Shared {
+        async fn get(&self) -> u8;
+    }
+
+    pub struct Value(u8);
+
+    #[async_trait(?Send if cfg(all()))]
+    impl Local for Value {
+        async fn get(&self) -> u8 {
+            let value = Rc::new(self.0);
+            async {}.await;
+            *value
+        }
+    }
+
+    #[async_trait(?Send if cfg(any()))]
+    impl Shared for Value {
+        async fn get(&self) -> u8 {
+            self.0
+        }
+    }
+
+    fn assert_send<T: Send>(future: T) -> T {
+        future
+    }
+
+    #[test]
+    fn test() {
+        let value = Value(1);
+        assert_eq!(executor::block_on_simple(Local::get(&value)), 1);
+        let future = assert_send(Shared::get(&value));
+        assert_eq!(executor::block_on_simple(future), 1);
+    }
+}
+
+pub mod receiver_bounds {
+    use crate::executor;
+    use async_trait::async_trait;
+    use std::marker::PhantomData;
+    use std::pin::Pin;
+    use std::sync::Arc;
+
+    #[async_trait]
+    pub trait Trait {
+        fn value(&self) -> u8;
+
+        async fn pinned(self: Pin<&Self>) -> u8 {
+            self.value()
+        }
+
+        async fn arc_ref(self: &Arc<Self>) -> u8 {
+            self.value()
+        }
+    }
+
+    // Sync but not Send, which is enough for receivers that only share Self.
+    pub struct Shared(u8, PhantomData<*const ()>);
+
+    unsafe impl Sync for Shared {}
+
+    #[async_trait]
+    impl Trait for Shared {
+        fn value(&self) -> u8 {
+            self.0
+        }
+    }
+
+    pub struct Plain(u8);
+
+    #[async_trait]
+    impl Trait for Plain {
+        fn value(&self) -> u8 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test() {
+        let shared = Shared(1, PhantomData);
+        assert_eq!(executor::block_on_simple(Pin::new(&shared).pinned()), 1);
+        assert_eq!(executor::block_on_simple(Arc::new(Plain(2)).arc_ref()), 2);
+    }
+
+    #[cfg(async_trait_nightly_testing)]
+    pub mod pointer {
+        use super::Shared;
+        use crate::executor;
+        use async_trait::async_trait;
+        use std::marker::PhantomData;
+        use std::ops::Deref;
+
+        pub struct Guard<'a, T: ?Sized>(&'a T);
+
+        impl<'a, T: ?Sized> Deref for Guard<'a, T> {
+            type Target = T;
+
+            fn deref(&self) -> &T {
+                self.0
+            }
+        }
+
+        #[async_trait(receiver(Guard = &))]
+        pub trait Trait {
+            fn value(&self) -> u8;
+
+            async fn guard(self: Guard<'_, Self>) -> u8 {
+                self.value()
+            }
+        }
+
+        #[async_trait(receiver(Guard = &))]
+        impl Trait for Shared {
+            fn value(&self) -> u8 {
+                self.0
+            }
+        }
+
+        #[test]
+        fn test() {
+            let shared = Shared(1, PhantomData);
+            assert_eq!(executor::block_on_simple(Guard(&shared).guard()), 1);
+        }
+    }
+}
+
+pub mod implied_bounds {
+    use crate::executor;
+    use async_trait::async_trait;
+
+    pub trait ThreadSafe: Send + Sync {}
+
+    impl<T: Send + Sync> ThreadSafe for T {}
+
+    #[async_trait(implies(ThreadSafe = Send + Sync))]
+    pub trait Service: ThreadSafe {
+        fn id(&self) -> u8;
+
+        async fn call(&self) -> u8 {
+            self.id()
+        }
+
+        async fn call_mut(&mut self) -> u8 {
+            self.id()
+        }
+    }
+
+    pub struct Echo(u8);
+
+    #[async_trait(implies(ThreadSafe = Send + Sync))]
+    impl Service for Echo {
+        fn id(&self) -> u8 {
+            self.0
+        }
+    }
+
+    async fn call_dyn(service: &mut dyn Service) -> u8 {
+        service.call().await + service.call_mut().await
+    }
+
+    #[test]
+    fn test() {
+        assert_eq!(executor::block_on_simple(call_dyn(&mut Echo(1))), 2);
+    }
+}
+
+pub mod track_caller {
+    use crate::executor;
+    use async_trait::async_trait;
+    use std::panic::Location;
+
+    #[async_trait]
+    pub trait Trait {
+        #[track_caller]
+        async fn default_location(&self) -> &'static Location<'static> {
+            Location::caller()
+        }
+
+        async fn location(&self) -> &'static Location<'static>;
+    }
+
+    pub struct Struct;
+
+    #[async_trait]
+    impl Trait for Struct {
+        #[track_caller]
+        async fn location(&self) -> &'static Location<'static> {
+            let _ = async { Location::caller() }.await;
+            Location::caller()
+        }
+    }
+
+    #[test]
+    fn test() {
+        let (future, line) = (Struct.default_location(), line!());
+        assert_eq!(executor::block_on_simple(future).line(), line);
+        let (future, line) = (Struct.location(), line!());
+        let location = executor::block_on_simple(future);
+        assert_eq!((location.file(), location.line()), (file!(), line));
+    }
+}
+
+pub mod routed_attrs {
+    use crate::executor;
+    use async_trait::async_trait;
+
+    #[async_trait]
+    pub trait Trait {
+        #[async_trait::outer(must_use)]
+        #[async_trait::inner(allow(unused_variables))]
+        async fn f(&self, unused: u8) -> u8 {
+            1
+        }
+
+        async fn g(&self) -> u8;
+    }
+
+    pub struct Struct;
+
+    #[async_trait]
+    impl Trait for Struct {
+        #[async_trait::inner(allow(unused_variables))]
+        async fn g(&self) -> u8 {
+            let unused = 0;
+            2
+        }
+    }
+
+    #[async_trait::async_trait]
+    #[async_trait::inner(allow(unused_variables))]
+    async fn free() {
+        let unused = 0;
+    }
+
+    #[test]
+    fn test() {
+        executor::block_on_simple(free());
+        assert_eq!(executor::block_on_simple(Struct.f(0)), 1);
+        assert_eq!(executor::block_on_simple(Struct.g()), 2);
+    }
+}
+
+pub mod instrument {
+    use crate::executor;
+    use async_trait::async_trait;
+    use std::future::Future;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+    use tracing::event::Event;
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{info, subscriber, Metadata, Subscriber};
+
+    #[async_trait]
+    pub trait Service {
+        async fn call(&self, request: u8, secret: u8) -> u8;
+
+        fn double(&self, n: u8) -> impl Future<Output = u8> + Send;
+
+        #[async_trait(instrument(level = "debug"))]
+        async fn ready(&self) -> bool {
+            info!("ready");
+            true
+        }
+    }
+
+    pub struct Echo;
+
+    #[async_trait(instrument(skip(secret)))]
+    impl Service for Echo {
+        async fn call(&self, request: u8, secret: u8) -> u8 {
+            info!("call");
+            request + secret
+        }
+
+        fn double(&self, n: u8) -> impl Future<Output = u8> + Send {
+            async move {
+                info!("double");
+                n * 2
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct Inner {
+        next_id: AtomicU64,
+        depth: AtomicU64,
+        // Name, level and fields of every span, in order of creation.
+        spans: Mutex<Vec<(&'static str, String, Vec<&'static str>)>>,
+        // Depth of the span stack whenever an event was recorded.
+        events: Mutex<Vec<u64>>,
+    }
+
+    #[derive(Clone, Default)]
+    struct TestSubscriber(Arc<Inner>);
+
+    impl Subscriber for TestSubscriber {
+        fn enabled(&self, _metadata: &Metadata) -> bool {
+            true
+        }
+        fn new_span(&self, span: &Attributes) -> Id {
+            let metadata = span.metadata();
+            let fields = metadata.fields().iter().map(|field| field.name()).collect();
+            let level = metadata.level().to_string();
+            let spans = &mut self.0.spans.lock().unwrap();
+            spans.push((metadata.name(), level, fields));
+            Id::from_u64(self.0.next_id.fetch_add(1, Ordering::AcqRel) + 1)
+        }
+        fn record(&self, _span: &Id, _values: &Record) {}
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+        fn event(&self, _event: &Event) {
+            let depth = self.0.depth.load(Ordering::Acquire);
+            self.0.events.lock().unwrap().push(depth);
+        }
+        fn enter(&self, _span: &Id) {
+            self.0.depth.fetch_add(1, Ordering::AcqRel);
+        }
+        fn exit(&self, _span: &Id) {
+            self.0.depth.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+
+    #[test]
+    fn test() {
+        let subscriber = TestSubscriber::default();
+        subscriber::with_default(subscriber.clone(), || {
+            assert_eq!(executor::block_on_simple(Echo.call(1, 2)), 3);
+            assert!(executor::block_on_simple(Echo.ready()));
+            assert_eq!(executor::block_on_simple(Echo.double(2)), 4);
+        });
+        let spans = subscriber.0.spans.lock().unwrap();
+        assert_eq!(
+            spans[0],
+            ("Service::call", "INFO".to_owned(), vec!["request"])
+        );
+        assert_eq!(spans[1], ("Service::ready", "DEBUG".to_owned(), vec![]));
+        assert_eq!(spans[2], ("Service::double", "INFO".to_owned(), vec!["n"]));
+        assert_eq!(*subscriber.0.events.lock().unwrap(), [1, 1, 1]);
+    }
+}
+
+pub mod wrap {
+    use crate::executor;
+    use async_trait::async_trait;
+    use std::future::Future;
+    use std::sync::Mutex;
+
+    static CALLS: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
+    pub async fn observe<F: Future>(name: &'static str, future: F) -> F::Output {
+        let output = future.await;
+        CALLS.lock().unwrap().push(name);
+        output
+    }
+
+    #[async_trait(wrap = observe)]
+    pub trait Trait {
+        async fn f(&self) -> u8;
+
+        async fn g(&self) -> u8 {
+            self.f().await + 1
+        }
+
+        fn h(&self) -> impl Future<Output = u8> + Send {
+            async { 0 }
+        }
+    }
+
+    pub struct Struct;
+
+    #[async_trait(wrap = observe)]
+    impl Trait for Struct {
+        async fn f(&self) -> u8 {
+            1
+        }
+    }
+
+    fn assert_send<T: Send>(value: T) -> T {
+        value
+    }
+
+    #[test]
+    fn test() {
+        let future = assert_send(Struct.g());
+        assert_eq!(executor::block_on_simple(future), 2);
+        assert_eq!(executor::block_on_simple(Struct.h()), 0);
+        let calls = CALLS.lock().unwrap();
+        assert_eq!(*calls, ["Trait::f", "Trait::g", "Trait::h"]);
+    }
+}
+
+pub mod inherent_impl {
+    use crate::executor;
+    use async_trait::async_trait;
+
+    pub struct Counter(u8);
+
+    #[async_trait]
+    impl Counter {
+        pub async fn get(&self) -> u8 {
+            self.0
+        }
+
+        async fn recursive(&mut self, n: u8) -> u8 {
+            if n == 0 {
+                self.get().await
+            } else {
+                self.0 += 1;
+                self.recursive(n - 1).await
+            }
+        }
+
+        pub fn sync(&self) -> u8 {
+            self.0
+        }
+
+        // Return position impl Trait is fine outside of traits, so the
+        // iterator is neither boxed nor stripped of its auto traits.
+        pub async fn range(&self) -> impl Iterator<Item = u8> {
+            0..self.0
+        }
+    }
+
+    fn assert_send<T: Send>(_: T) {}
 
-    async fn selfmut(&mut self) {}
+    #[test]
+    fn test() {
+        let mut counter = Counter(0);
+        assert_send(counter.recursive(2));
+        assert_eq!(executor::block_on_simple(counter.recursive(3)), 3);
+        assert_eq!(counter.sync(), 3);
+        let range = executor::block_on_simple(counter.range());
+        assert_send(&range);
+        assert_eq!(range.sum::<u8>(), 3);
+    }
+}
 
-    async fn required() -> Self::Assoc;
+pub mod free_fn {
+    use crate::executor;
+    use async_trait::async_trait;
+    use std::rc::Rc;
 
-    async fn elided_lifetime(_x: &str) {}
+    pub struct Tree {
+        pub value: u8,
+        pub children: Vec<Tree>,
+    }
 
-    async fn explicit_lifetime<'a>(_x: &'a str) {}
+    #[async_trait]
+    pub async fn sum(tree: &Tree, (depth, _): (usize, ())) -> usize {
+        let mut sum = tree.value as usize * depth;
+        for child in &tree.children {
+            sum += sum_children(child, depth + 1).await;
+        }
+        sum
+    }
 
-    async fn generic_type_param<T: Send>(x: Box<T>) -> T {
-        *x
+    #[async_trait]
+    async fn sum_children(tree: &Tree, depth: usize) -> usize {
+        sum(tree, (depth, ())).await
     }
 
-    async fn calls(&self) {
-        self.selfref().await;
-        Self::elided_lifetime("").await;
-        <Self>::elided_lifetime("").await;
+    #[async_trait(?Send)]
+    async fn local(rc: Rc<u8>, n: u8) -> u8 {
+        if n == 0 {
+            async {}.await;
+            *rc
+        } else {
+            local(rc, n - 1).await + 1
+        }
     }
 
-    async fn calls_mut(&mut self) {
-        self.selfmut().await;
+    fn assert_send<T: Send>(_: T) {}
+
+    #[test]
+    fn test() {
+        let tree = Tree {
+            value: 1,
+            children: vec![Tree {
+                value: 2,
+                children: Vec::new(),
+            }],
+        };
+        assert_send(sum(&tree, (1, ())));
+        assert_eq!(executor::block_on_simple(sum(&tree, (1, ()))), 5);
+        assert_eq!(executor::block_on_simple(local(Rc::new(1), 2)), 3);
     }
 }
 
-struct Struct;
-
-#[async_trait]
-impl Trait for Struct {
-    type Assoc = ();
+pub mod mock {
+    use crate::executor;
+    use async_trait::async_trait;
 
-    async fn selfvalue(self) {}
+    #[async_trait(mock)]
+    pub trait Repository<K> {
+        fn name(&self) -> &'static str;
 
-    async fn selfref(&self) {}
+        async fn get(&self, key: K, prefix: &str) -> Option<String>;
 
-    async fn selfmut(&mut self) {}
+        async fn put(&mut self, key: K, value: String);
 
-    async fn required() -> Self::Assoc {}
+        async fn count(&self) -> usize {
+            0
+        }
+    }
 
-    async fn elided_lifetime(_x: &str) {}
+    pub async fn lookup(repository: &(dyn Repository<u8> + Sync), key: u8) -> String {
+        let value = repository.get(key, "value: ").await;
+        value.unwrap_or_default()
+    }
 
-    async fn explicit_lifetime<'a>(_x: &'a str) {}
+    #[test]
+    fn test() {
+        let mut mock = MockRepository::new();
+        mock.expect_name(|| "mock")
+            .expect_get(|key, prefix| Some(format!("{}{}", prefix, key)))
+            .expect_get(|_, _| None)
+            .expect_put(|key, value| assert_eq!((key, value.as_str()), (1, "one")))
+            .expect_count(|| 2);
 
-    async fn generic_type_param<T: Send>(x: Box<T>) -> T {
-        *x
+        assert_eq!(mock.name(), "mock");
+        assert_eq!(executor::block_on_simple(lookup(&mock, 1)), "value: 1");
+        assert_eq!(executor::block_on_simple(lookup(&mock, 2)), "");
+        executor::block_on_simple(mock.put(1, "one".to_owned()));
+        assert_eq!(executor::block_on_simple(mock.count()), 2);
     }
 
-    async fn calls(&self) {
-        self.selfref().await;
-        Self::elided_lifetime("").await;
-        <Self>::elided_lifetime("").await;
+    #[test]
+    #[should_panic = "unexpected call to MockRepository::count"]
+    fn test_unexpected() {
+        let mock = MockRepository::<u8>::default();
+        executor::block_on_simple(mock.count());
     }
 
-    async fn calls_mut(&mut self) {
-        self.selfmut().await;
+    #[test]
+    #[should_panic = "MockRepository::name: 1 expected call(s) were not made"]
+    fn test_unused() {
+        let mut mock = MockRepository::<u8>::new();
+        mock.expect_name(|| "unused");
     }
 }
 
-pub async fn test() {
-    let mut s = Struct;
-    s.selfref().await;
-    s.selfmut().await;
-    s.selfvalue().await;
-
-    Struct::required().await;
-    Struct::elided_lifetime("").await;
-    Struct::explicit_lifetime("").await;
-    Struct::generic_type_param(Box::new("")).await;
+pub mod future_path {
+    use crate::executor;
+    use async_trait::async_trait;
+    use futures::future::{BoxFuture, LocalBoxFuture};
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::rc::Rc;
+    use std::task::{Context, Poll};
 
-    let mut s = Struct;
-    s.calls().await;
-    s.calls_mut().await;
-}
+    #[async_trait(future = BoxFuture)]
+    pub trait Trait {
+        async fn f(&self, x: &u8) -> u8;
 
-pub async fn test_dyn_compatible_without_default() {
-    #[async_trait]
-    trait DynCompatible {
-        async fn f(&self);
+        async fn g(&self) -> u8 {
+            self.f(&1).await
+        }
     }
 
-    #[async_trait]
-    impl DynCompatible for Struct {
-        async fn f(&self) {}
+    pub struct Struct;
+
+    #[async_trait(future = BoxFuture)]
+    impl Trait for Struct {
+        async fn f(&self, x: &u8) -> u8 {
+            *x
+        }
     }
 
-    let object = &Struct as &dyn DynCompatible;
-    object.f().await;
-}
+    pub fn boxed(object: &(dyn Trait + Sync)) -> BoxFuture<'_, u8> {
+        object.g()
+    }
 
-pub async fn test_dyn_compatible_with_default() {
-    #[async_trait]
-    trait DynCompatible: Sync {
-        async fn f(&self) {}
+    #[async_trait(?Send, future = LocalBoxFuture)]
+    pub trait LocalTrait {
+        async fn f(&self, rc: Rc<u8>) -> u8 {
+            async {}.await;
+            *rc
+        }
     }
 
-    #[async_trait]
-    impl DynCompatible for Struct {
-        async fn f(&self) {}
+    pub struct Pooled<'a, T>(Pin<Box<dyn Future<Output = T> + Send + 'a>>);
+
+    impl<'a, T> From<Pin<Box<dyn Future<Output = T> + Send + 'a>>> for Pooled<'a, T> {
+        fn from(future: Pin<Box<dyn Future<Output = T> + Send + 'a>>) -> Self {
+            Pooled(future)
+        }
     }
 
-    let object = &Struct as &dyn DynCompatible;
-    object.f().await;
-}
+    impl<'a, T> Future for Pooled<'a, T> {
+        type Output = T;
 
-pub async fn test_dyn_compatible_no_send() {
-    #[async_trait(?Send)]
-    trait DynCompatible: Sync {
-        async fn f(&self) {}
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+            self.0.as_mut().poll(cx)
+        }
     }
 
-    #[async_trait(?Send)]
-    impl DynCompatible for Struct {
-        async fn f(&self) {}
+    #[async_trait(future = Pooled)]
+    pub trait PooledTrait {
+        async fn f(&self) -> &'static str;
     }
 
-    let object = &Struct as &dyn DynCompatible;
-    object.f().await;
+    #[async_trait(future = self::Pooled)]
+    impl PooledTrait for Struct {
+        async fn f(&self) -> &'static str {
+            "pooled"
+        }
+    }
+
+    #[test]
+    fn test() {
+        assert_eq!(executor::block_on_simple(boxed(&Struct)), 1);
+        assert_eq!(executor::block_on_simple(PooledTrait::f(&Struct)), "pooled");
+    }
 }
 
-#[async_trait]
-pub unsafe trait UnsafeTrait {}
+#[rustversion::since(1.75)]
+pub mod dyn_twin {
+    use crate::executor;
+    use async_trait::async_trait;
+    use std::future::Future;
+    use std::rc::Rc;
 
-#[async_trait]
-unsafe impl UnsafeTrait for () {}
+    #[async_trait(dyn = DynStore)]
+    pub trait Store<K> {
+        type Value;
+        const NAME: &'static str;
 
-#[async_trait]
-#[allow(dead_code)]
-pub(crate) unsafe trait UnsafeTraitPubCrate {}
+        fn count(&self) -> usize;
 
-#[async_trait]
-#[allow(dead_code)]
-unsafe trait UnsafeTraitPrivate {}
+        async fn get(&self, key: K) -> Option<Self::Value>;
 
-pub async fn test_can_destruct() {
-    #[async_trait]
-    trait CanDestruct {
-        async fn f(&self, foos: (u8, u8, u8, u8));
-    }
+        async fn insert(&mut self, key: K, value: Self::Value);
 
-    #[async_trait]
-    impl CanDestruct for Struct {
-        async fn f(&self, (a, ref mut b, ref c, d): (u8, u8, u8, u8)) {
-            let _a: u8 = a;
-            let _b: &mut u8 = b;
-            let _c: &u8 = c;
-            let _d: u8 = d;
+        async fn keys(&self) -> impl Iterator<Item = K> + Send;
+
+        fn len(&self, key: &str) -> impl Future<Output = usize> + Send;
+
+        #[async_trait::inner(allow(unused_variables))]
+        async fn describe(&self) -> String {
+            let unused = ();
+            format!("{} with {} entries", Self::NAME, self.count())
+        }
+
+        #[async_trait(?Send)]
+        async fn local(&self, (a, b): (Rc<u8>, u8)) -> u8 {
+            async {}.await;
+            *a + b
         }
     }
 
-    let _ = <Struct as CanDestruct>::f;
-}
+    pub struct VecStore(Vec<(u8, String)>);
 
-pub async fn test_self_in_macro() {
-    #[async_trait]
-    trait Trait {
-        async fn a(self);
-        async fn b(&mut self);
-        async fn c(&self);
-    }
+    // Implemented natively, without the macro.
+    impl Store<u8> for VecStore {
+        type Value = String;
+        const NAME: &'static str = "VecStore";
 
-    #[async_trait]
-    impl Trait for String {
-        async fn a(self) {
-            println!("{}", self);
+        fn count(&self) -> usize {
+            self.0.len()
         }
-        async fn b(&mut self) {
-            println!("{}", self);
+
+        async fn get(&self, key: u8) -> Option<String> {
+            let entry = self.0.iter().find(|entry| entry.0 == key)?;
+            Some(entry.1.clone())
         }
-        async fn c(&self) {
-            println!("{}", self);
+
+        async fn insert(&mut self, key: u8, value: String) {
+            self.0.push((key, value));
         }
-    }
 
-    let _ = <String as Trait>::a;
-    let _ = <String as Trait>::b;
-    let _ = <String as Trait>::c;
-}
+        async fn keys(&self) -> impl Iterator<Item = u8> + Send {
+            let keys: Vec<u8> = self.0.iter().map(|entry| entry.0).collect();
+            keys.into_iter()
+        }
 
-pub async fn test_inference() {
-    #[async_trait]
-    pub trait Trait {
-        async fn f() -> Box<dyn Iterator<Item = ()>> {
-            Box::new(std::iter::empty())
+        fn len(&self, key: &str) -> impl Future<Output = usize> + Send {
+            let len = key.len();
+            async move { len }
         }
     }
 
-    impl Trait for () {}
+    fn assert_send<T: Send>(_: T) {}
 
-    let _ = <() as Trait>::f;
-}
+    pub async fn generic<S: Store<u8, Value = String> + Sync>(store: &S) -> Option<String> {
+        assert_send(store.get(0));
+        store.get(0).await
+    }
 
-pub async fn test_internal_items() {
-    #[async_trait]
-    #[allow(dead_code, clippy::items_after_statements)]
-    pub trait Trait: Sized {
-        async fn f(self) {
-            struct Struct;
+    #[test]
+    fn test() {
+        let mut store: Box<dyn DynStore<u8, Value = String> + Send + Sync> =
+            Box::new(VecStore(Vec::new()));
+        executor::block_on_simple(store.insert(1, "one".to_owned()));
+        assert_send(store.get(1));
+        assert_eq!(store.count(), 1);
+        let keys = executor::block_on_simple(store.keys());
+        assert_eq!(keys.collect::<Vec<_>>(), [1]);
+        assert_send(store.len("one"));
+        assert_eq!(executor::block_on_simple(store.len("one")), 3);
+        assert_eq!(
+            executor::block_on_simple(store.get(1)).as_deref(),
+            Some("one")
+        );
+        assert_eq!(
+            executor::block_on_simple(store.describe()),
+            "VecStore with 1 entries",
+        );
+        assert_eq!(executor::block_on_simple(store.local((Rc::new(1), 2))), 3);
+    }
+}
+
+pub mod variants {
+    use crate::executor;
+    use async_trait::async_trait;
+    use std::cell::Cell;
+    use std::rc::Rc;
 
-            impl Struct {
-                fn f(self) {
-                    let _ = self;
-                }
-            }
+    #[async_trait(variants(Send = SendCounter, Local = Counter))]
+    pub trait Counter<T> {
+        type Step;
+        const START: u8;
+
+        fn step(&self) -> Self::Step;
+
+        async fn add(&self, value: T) -> u8;
+
+        async fn add_twice(&self, value: T) -> u8
+        where
+            T: Clone + Send + 'async_trait,
+        {
+            self.add(value.clone()).await;
+            self.add(value).await
         }
     }
-}
 
-pub async fn test_unimplemented() {
+    pub struct Atomic(std::sync::atomic::AtomicU8);
+
     #[async_trait]
-    pub trait Trait {
-        async fn f() {
-            unimplemented!()
+    impl SendCounter<u8> for Atomic {
+        type Step = ();
+        const START: u8 = 0;
+
+        fn step(&self) {}
+
+        async fn add(&self, value: u8) -> u8 {
+            use std::sync::atomic::Ordering;
+            self.0.fetch_add(value, Ordering::Relaxed) + value
         }
     }
 
-    impl Trait for () {}
+    pub struct Shared(Rc<Cell<u8>>);
 
-    let _ = <() as Trait>::f;
+    #[async_trait(?Send)]
+    impl Counter<u8> for Shared {
+        type Step = ();
+        const START: u8 = 0;
+
+        fn step(&self) {}
+
+        async fn add(&self, value: u8) -> u8 {
+            let rc = self.0.clone();
+            async {}.await;
+            rc.set(rc.get() + value);
+            rc.get()
+        }
+    }
+
+    fn assert_send<T: Send>(_: T) {}
+
+    #[test]
+    fn test() {
+        let atomic = Atomic(Default::default());
+        assert_send(SendCounter::add(&atomic, 1));
+
+        fn count<C: Counter<u8>>(counter: C) {
+            assert_eq!(C::START, 0);
+            assert_eq!(executor::block_on_simple(counter.add(1)), 1);
+            assert_eq!(executor::block_on_simple(counter.add_twice(2)), 5);
+        }
+        count(atomic);
+        count(Shared(Default::default()));
+    }
 }
 
 // https://github.com/dtolnay/async-trait/issues/1
@@ -734,6 +1954,46 @@ pub mod issue53 {
             let _ = Self;
         }
     }
+
+    pub struct Generic<'a, T>(pub &'a T, pub u8);
+
+    #[async_trait]
+    impl<T: Sync> Trait for Generic<'static, T> {
+        async fn method() {
+            let _ = |value: Self| {
+                let Self(inner, n) = value;
+                Self(inner, n + 1)
+            };
+        }
+    }
+
+    pub enum Enum<T> {
+        A(T),
+        B { x: u8 },
+    }
+
+    #[async_trait]
+    impl<T: Default + Send> Trait for Enum<T> {
+        async fn method() {
+            match Self::A(T::default()) {
+                Self::A(_) | Self::B { .. } => {}
+            }
+        }
+    }
+
+    pub type Alias = Tuple;
+
+    #[async_trait]
+    pub trait Alternate {
+        async fn method();
+    }
+
+    #[async_trait]
+    impl Alternate for Alias {
+        async fn method() {
+            let _ = Self(0);
+        }
+    }
 }
 
 // https://github.com/dtolnay/async-trait/issues/57
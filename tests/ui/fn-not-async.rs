@@ -0,0 +1,6 @@
+use async_trait::async_trait;
+
+#[async_trait]
+pub fn f() {}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use async_trait::async_trait;
+
+#[async_trait(forward(Box, Arc))]
+pub trait Trait {
+    async fn get(&self) -> u8;
+    async fn set(&mut self, value: u8);
+}
+
+fn main() {}
@@ -0,0 +1,17 @@
+use async_trait::async_trait;
+
+#[async_trait]
+pub trait Trait {
+    #[async_trait::inner(allow(unused_variables))]
+    async fn f(&self);
+}
+
+pub struct Struct;
+
+#[async_trait]
+impl Trait for Struct {
+    #[async_trait::inner(allow(unused_variables))]
+    async fn f(&self);
+}
+
+fn main() {}
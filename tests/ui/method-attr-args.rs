@@ -0,0 +1,15 @@
+use async_trait::async_trait;
+
+#[async_trait]
+pub trait Trait {
+    #[async_trait]
+    async fn method();
+}
+
+#[async_trait]
+pub trait Lifetime {
+    #[async_trait('a)]
+    async fn method();
+}
+
+fn main() {}
@@ -0,0 +1,21 @@
+use async_trait::async_trait;
+
+#[async_trait]
+pub trait Trait {
+    #[async_trait(?Send)]
+    async fn local(&self);
+
+    async fn shared(&self);
+}
+
+pub struct Struct;
+
+#[async_trait]
+impl Trait for Struct {
+    async fn local(&self) {}
+
+    #[async_trait(?Send)]
+    async fn shared(&self) {}
+}
+
+fn main() {}
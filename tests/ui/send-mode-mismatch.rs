@@ -0,0 +1,40 @@
+use async_trait::async_trait;
+
+#[async_trait(?Send)]
+pub trait Local {
+    async fn f(&self);
+}
+
+#[async_trait]
+pub trait Shared {
+    async fn f(&self);
+}
+
+#[async_trait(?Send)]
+pub trait Handler<M> {
+    async fn handle(&self, message: M);
+}
+
+pub struct Struct;
+
+#[async_trait]
+impl Local for Struct {
+    async fn f(&self) {}
+}
+
+#[async_trait(?Send)]
+impl Shared for Struct {
+    async fn f(&self) {}
+}
+
+#[async_trait(?Send)]
+impl Handler<u8> for Struct {
+    async fn handle(&self, _message: u8) {}
+}
+
+#[async_trait]
+impl Handler<u16> for Struct {
+    async fn handle(&self, _message: u16) {}
+}
+
+fn main() {}
@@ -0,0 +1,50 @@
+use async_trait::async_trait;
+
+mod shared {
+    use async_trait::async_trait;
+
+    #[async_trait]
+    pub trait Handler {
+        async fn f(&self);
+    }
+}
+
+mod local {
+    use async_trait::async_trait;
+
+    #[async_trait(?Send)]
+    pub trait Handler {
+        async fn f(&self);
+    }
+}
+
+#[allow(unused_imports)]
+use local::Handler as _;
+#[allow(unused_imports)]
+use shared::Handler as _;
+
+pub struct Both;
+
+#[async_trait]
+impl shared::Handler for Both {
+    async fn f(&self) {}
+}
+
+#[async_trait(?Send)]
+impl local::Handler for Both {
+    async fn f(&self) {}
+}
+
+pub struct Mismatch;
+
+#[async_trait]
+impl shared::Handler for Mismatch {
+    async fn f(&self) {}
+}
+
+#[async_trait]
+impl local::Handler for Mismatch {
+    async fn f(&self) {}
+}
+
+fn main() {}
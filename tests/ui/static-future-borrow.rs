@@ -0,0 +1,9 @@
+use async_trait::async_trait;
+
+#[async_trait]
+pub trait Trait {
+    #[async_trait('static)]
+    async fn f(self: Box<Self>, data: &[u8]);
+}
+
+fn main() {}